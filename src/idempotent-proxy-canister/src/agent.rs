@@ -6,12 +6,24 @@ use ic_cdk::api::management_canister::http_request::{
 };
 use serde::{Deserialize, Serialize};
 
+// Bytes of the outcall limit reserved for headers of load balancers in front of the proxy.
+const MAX_RESPONSE_BYTES_HEADROOM: u64 = 1024;
+
 #[derive(CandidType, Default, Clone, Deserialize, Serialize)]
 pub struct Agent {
     pub name: String, // used as a prefix for idempotency_key and message in sign_proxy_token to separate different business processes.
     pub endpoint: String,
     pub max_cycles: u64,
     pub proxy_token: Option<String>,
+    // If set, a v2 proxy token bound to this audience (the proxy's PROXY_AUDIENCE) is issued.
+    #[serde(default)]
+    pub audience: Option<String>,
+    // Hosts the v2 proxy token may access, None means any host.
+    #[serde(default)]
+    pub hosts: Option<Vec<String>>,
+    // URL vars the v2 proxy token may access, None means any URL var.
+    #[serde(default)]
+    pub url_vars: Option<Vec<String>>,
 }
 
 impl Agent {
@@ -47,6 +59,19 @@ impl Agent {
             });
         }
 
+        // The proxy returns a small 413 response instead of a response that would fail the outcall.
+        // The limit leaves room for headers added between the proxy and the replicas.
+        if let Some(max_response_bytes) = req.max_response_bytes {
+            if !req.headers.iter().any(|h| h.name == "max-response-bytes") {
+                req.headers.push(HttpHeader {
+                    name: "max-response-bytes".to_string(),
+                    value: max_response_bytes
+                        .saturating_sub(MAX_RESPONSE_BYTES_HEADROOM)
+                        .to_string(),
+                });
+            }
+        }
+
         if let Some(proxy_token) = &self.proxy_token {
             req.headers.push(HttpHeader {
                 name: "proxy-authorization".to_string(),
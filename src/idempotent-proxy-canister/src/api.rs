@@ -38,6 +38,9 @@ fn state_info() -> StateInfo {
                 endpoint: a.endpoint.clone(),
                 max_cycles: a.max_cycles,
                 proxy_token: None,
+                audience: a.audience.clone(),
+                hosts: a.hosts.clone(),
+                url_vars: a.url_vars.clone(),
             })
             .collect(),
         managers: s.managers.clone(),
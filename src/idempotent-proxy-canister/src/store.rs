@@ -7,6 +7,7 @@ use ic_stable_structures::{
     storable::Bound,
     DefaultMemoryImpl, StableCell, Storable,
 };
+use idempotent_proxy_types::auth::Claims;
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use std::{
@@ -92,14 +93,32 @@ impl Signer {
 
     // use Idempotent Proxy's Token: Token(pub u64, pub String, pub ByteBuf);
     // https://github.com/ldclabs/idempotent-proxy/blob/main/src/idempotent-proxy-types/src/auth.rs#L15
+    // or the v2 token with audience and scope if agent.audience is set.
     pub async fn sign_proxy_token(
         &self,
+        issued_at: u64, // UNIX timestamp, in seconds
         expire_at: u64, // UNIX timestamp, in seconds
-        message: &str,  // use RPCAgent.name as message
+        agent: &Agent,  // use Agent.name as message
     ) -> Result<String, String> {
-        let mut buf: Vec<u8> = Vec::new();
-        into_writer(&(expire_at, message), &mut buf)
-            .expect("failed to encode Token in CBOR format");
+        let claims = agent.audience.as_ref().map(|audience| Claims {
+            expire_at,
+            issued_at,
+            agent: agent.name.clone(),
+            audience: audience.clone(),
+            hosts: agent.hosts.clone(),
+            url_vars: agent.url_vars.clone(),
+            kid: None,
+        });
+
+        let buf = match claims {
+            Some(ref claims) => claims.to_message(),
+            None => {
+                let mut buf: Vec<u8> = Vec::new();
+                into_writer(&(expire_at, &agent.name), &mut buf)
+                    .expect("failed to encode Token in CBOR format");
+                buf
+            }
+        };
         let digest = sha3_256(&buf);
 
         let sig = match self.cose {
@@ -115,9 +134,15 @@ impl Signer {
                 .map(ByteBuf::from),
         };
 
-        buf.clear();
-        into_writer(&(expire_at, message, sig?), &mut buf).map_err(format_error)?;
-        Ok(base64_url.encode(buf))
+        let token = match claims {
+            Some(ref claims) => claims.to_token(&sig?),
+            None => {
+                let mut buf: Vec<u8> = Vec::new();
+                into_writer(&(expire_at, &agent.name, sig?), &mut buf).map_err(format_error)?;
+                buf
+            }
+        };
+        Ok(base64_url.encode(token))
     }
 }
 
@@ -19,21 +19,31 @@ pub async fn update_proxy_token(
         return;
     }
 
-    let mut tokens: BTreeMap<String, String> = BTreeMap::new();
+    type TokenScope = (
+        String,
+        Option<String>,
+        Option<Vec<String>>,
+        Option<Vec<String>>,
+    );
+    let mut tokens: BTreeMap<TokenScope, String> = BTreeMap::new();
     for agent in agents.iter_mut() {
-        if let Some(token) = tokens.get(&agent.name) {
+        let scope = (
+            agent.name.clone(),
+            agent.audience.clone(),
+            agent.hosts.clone(),
+            agent.url_vars.clone(),
+        );
+        if let Some(token) = tokens.get(&scope) {
             agent.proxy_token = Some(token.clone());
             continue;
         }
 
+        let now = ic_cdk::api::time() / SECONDS;
         let token = signer
-            .sign_proxy_token(
-                (ic_cdk::api::time() / SECONDS) + proxy_token_refresh_interval + 120,
-                &agent.name,
-            )
+            .sign_proxy_token(now, now + proxy_token_refresh_interval + 120, agent)
             .await
             .expect("failed to sign proxy token");
-        tokens.insert(agent.name.clone(), token.clone());
+        tokens.insert(scope, token.clone());
         agent.proxy_token = Some(token);
     }
 
@@ -0,0 +1,43 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use idempotent_proxy_server::cache::{Cacher, MemoryCacher};
+
+// Concurrent requests, each with a unique key: obtain, set, get and del.
+const TASKS: usize = 4096;
+
+// cargo bench -p idempotent-proxy-server --bench memory_cacher
+fn concurrent_keys(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(8)
+        .enable_all()
+        .build()
+        .unwrap();
+
+    let mut group = c.benchmark_group("memory_cacher");
+    group.throughput(Throughput::Elements((TASKS * 4) as u64));
+    for shards in [1, 16, 64] {
+        let mc = MemoryCacher::with_shards(shards, usize::MAX, usize::MAX);
+        group.bench_with_input(BenchmarkId::new("shards", shards), &mc, |b, mc| {
+            b.to_async(&rt).iter(|| async {
+                let tasks: Vec<_> = (0..TASKS)
+                    .map(|i| {
+                        let mc = mc.clone();
+                        tokio::spawn(async move {
+                            let key = format!("key-{}", i);
+                            let token = mc.obtain(&key, 60000).await.unwrap().unwrap();
+                            assert!(mc.set(&key, token, vec![1; 256], 60000).await.unwrap());
+                            assert!(mc.get(&key).await.unwrap().is_some());
+                            mc.del(&key).await.unwrap();
+                        })
+                    })
+                    .collect();
+                for task in tasks {
+                    task.await.unwrap();
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, concurrent_keys);
+criterion_main!(benches);
@@ -0,0 +1,445 @@
+use axum::{routing, Router};
+use http::HeaderValue;
+use idempotent_proxy_types::{
+    auth::KeyRegistry,
+    clock::{Clock, SystemClock},
+};
+use reqwest::{Client, ClientBuilder};
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    cache::{
+        Canonicalizer, HybridCacher, JsonTransform, ResponseCodec, ResponseSigner, SecretScrubber,
+        SECRET_PARAMS,
+    },
+    forward::{ForwardPolicy, HeaderPolicy},
+    handler::{self, AppState},
+};
+
+pub const APP_NAME: &str = env!("CARGO_PKG_NAME");
+pub const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Builds the idempotent proxy as an axum `Router`, to run it standalone or embed it
+/// in another axum service:
+///
+/// ```no_run
+/// use axum::{routing, Router};
+/// use idempotent_proxy_server::{cache, ProxyBuilder};
+///
+/// let cacher = cache::HybridCacher::new(100, 10000, Box::new(cache::MemoryCacher::default()));
+/// let proxy = ProxyBuilder::new(cacher)
+///     .with_url_var("URL_HTTPBIN", "https://httpbin.org/get")
+///     .build();
+/// let app: Router = Router::new()
+///     .route("/health", routing::get(|| async { "ok" }))
+///     .merge(proxy);
+/// ```
+///
+/// The proxy handles all paths not matched by other routes. Background tasks of the
+/// cacher backend, such as `MemoryCacher::spawn_sweeper`, are left to the caller.
+pub struct ProxyBuilder {
+    http_client: Option<Client>,
+    cacher: HybridCacher,
+    codec: ResponseCodec,
+    signer: Option<ResponseSigner>,
+    agents: BTreeSet<String>,
+    admin_agents: BTreeSet<String>,
+    url_vars: HashMap<String, String>,
+    header_vars: HashMap<String, HeaderValue>,
+    transforms: HashMap<String, JsonTransform>,
+    volatile_fields: BTreeSet<String>,
+    secret_params: BTreeSet<String>,
+    forward: Option<ForwardPolicy>,
+    keys: KeyRegistry,
+    audience: String,
+    ic_root_key: Vec<u8>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ProxyBuilder {
+    pub fn new(cacher: HybridCacher) -> Self {
+        Self {
+            http_client: None,
+            cacher,
+            codec: ResponseCodec::default(),
+            signer: None,
+            agents: BTreeSet::new(),
+            admin_agents: BTreeSet::new(),
+            url_vars: HashMap::new(),
+            header_vars: HashMap::new(),
+            transforms: HashMap::new(),
+            volatile_fields: BTreeSet::new(),
+            secret_params: SECRET_PARAMS.iter().map(|s| s.to_string()).collect(),
+            forward: None,
+            keys: KeyRegistry::default(),
+            audience: String::new(),
+            ic_root_key: Vec::new(),
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Sets the client of upstream requests. The default client times out after the
+    /// cache TTL of the cacher, as a lock must not expire before its request.
+    pub fn with_http_client(mut self, client: Client) -> Self {
+        self.http_client = Some(client);
+        self
+    }
+
+    pub fn with_codec(mut self, codec: ResponseCodec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_signer(mut self, signer: ResponseSigner) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Agents allowed to use the proxy, all agents if empty.
+    pub fn with_agents(mut self, agents: BTreeSet<String>) -> Self {
+        self.agents = agents;
+        self
+    }
+
+    /// Agents allowed to manage token revocations.
+    pub fn with_admin_agents(mut self, agents: BTreeSet<String>) -> Self {
+        self.admin_agents = agents;
+        self
+    }
+
+    /// Adds a `URL_*` var, requested by the "/URL_*" path.
+    pub fn with_url_var(mut self, name: &str, url: &str) -> Self {
+        self.url_vars.insert(name.to_string(), url.to_string());
+        self
+    }
+
+    /// Adds a `HEADER_*` var, replacing the request header values equal to its name.
+    pub fn with_header_var(mut self, name: &str, value: HeaderValue) -> Self {
+        self.header_vars.insert(name.to_string(), value);
+        self
+    }
+
+    /// Adds a named `TRANSFORM_*` JSON transform.
+    pub fn with_transform(mut self, name: &str, transform: JsonTransform) -> Self {
+        self.transforms.insert(name.to_string(), transform);
+        self
+    }
+
+    /// Fields removed from canonical JSON responses.
+    pub fn with_volatile_fields(mut self, fields: BTreeSet<String>) -> Self {
+        self.volatile_fields = fields;
+        self
+    }
+
+    /// Adds query parameters of `URL_*` vars to redact from responses, along with
+    /// the default `SECRET_PARAMS`.
+    pub fn with_secret_params(mut self, params: BTreeSet<String>) -> Self {
+        self.secret_params
+            .extend(params.into_iter().map(|s| s.to_ascii_lowercase()));
+        self
+    }
+
+    /// Sets the forwarding policy of the client headers. By default all headers but
+    /// the control headers are forwarded, with the proxy `User-Agent`.
+    pub fn with_forward_policy(mut self, policy: ForwardPolicy) -> Self {
+        self.forward = Some(policy);
+        self
+    }
+
+    /// Keys of the Bearer tokens, the proxy requires tokens if not empty.
+    pub fn with_keys(mut self, keys: KeyRegistry) -> Self {
+        self.keys = keys;
+        self
+    }
+
+    /// The proxy identifier, tokens must be issued for it if not empty.
+    pub fn with_audience(mut self, audience: &str) -> Self {
+        self.audience = audience.to_string();
+        self
+    }
+
+    /// The raw IC root public key, enables ICP tokens if not empty. Any canister can
+    /// sign ICP tokens, so the allowed agents should be set along with it.
+    pub fn with_ic_root_key(mut self, key: Vec<u8>) -> Self {
+        self.ic_root_key = key;
+        self
+    }
+
+    /// The clock of token and revocation expirations.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Builds the state shared by the handlers, to mount them on custom routes.
+    pub fn build_state(self) -> AppState {
+        let http_client = self.http_client.unwrap_or_else(|| {
+            ClientBuilder::new()
+                .http2_keep_alive_interval(Some(Duration::from_secs(25)))
+                .http2_keep_alive_timeout(Duration::from_secs(15))
+                .http2_keep_alive_while_idle(true)
+                .connect_timeout(Duration::from_secs(10))
+                .timeout(Duration::from_millis(self.cacher.cache_ttl))
+                .gzip(true)
+                .build()
+                .expect("failed to build http client")
+        });
+        let forward = self.forward.unwrap_or_else(|| {
+            ForwardPolicy::new(
+                HeaderPolicy::default(),
+                HeaderValue::from_str(&format!("{}/{}", APP_NAME, APP_VERSION))
+                    .expect("invalid user agent"),
+            )
+        });
+        if !self.ic_root_key.is_empty() && self.agents.is_empty() {
+            log::warn!(target: "server",
+                "ICP tokens are enabled without allowed agents, any canister can use the proxy");
+        }
+        let scrubber =
+            SecretScrubber::new(
+                self.url_vars
+                    .values()
+                    .flat_map(|v| SecretScrubber::url_secrets(v, &self.secret_params))
+                    .chain(self.header_vars.values().flat_map(|v| {
+                        SecretScrubber::header_secrets(v.to_str().unwrap_or_default())
+                    })),
+            );
+
+        AppState {
+            http_client: Arc::new(http_client),
+            cacher: Arc::new(self.cacher),
+            codec: Arc::new(self.codec),
+            signer: self.signer.map(Arc::new),
+            agents: Arc::new(self.agents),
+            admin_agents: Arc::new(self.admin_agents),
+            url_vars: Arc::new(self.url_vars),
+            header_vars: Arc::new(self.header_vars),
+            transforms: Arc::new(self.transforms),
+            canonicalizer: Arc::new(Canonicalizer::new(self.volatile_fields)),
+            scrubber: Arc::new(scrubber),
+            forward: Arc::new(forward),
+            keys: Arc::new(self.keys),
+            audience: Arc::new(self.audience),
+            ic_root_key: Arc::new(self.ic_root_key),
+            clock: self.clock,
+        }
+    }
+
+    /// Builds the router with the "/_admin/revocations", "/_ready" and proxy routes.
+    pub fn build(self) -> Router {
+        Router::new()
+            .route(
+                "/_admin/revocations",
+                routing::post(handler::add_revocation).delete(handler::remove_revocation),
+            )
+            .route("/_ready", routing::get(handler::ready))
+            .route("/*any", routing::any(handler::proxy))
+            .with_state(self.build_state())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{cache::MemoryCacher, tls::ClientIdentity};
+    use ciborium::cbor;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_proxy_builder() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let upstream = Router::new().route(
+            "/echo",
+            routing::get(move || async move {
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                format!("hit {}", n)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let cacher = HybridCacher::new(10, 10000, Box::new(MemoryCacher::default()));
+        let proxy = ProxyBuilder::new(cacher)
+            .with_url_var("URL_ECHO", &format!("http://{}/echo", upstream_addr))
+            .build();
+        // embedded with other routes, which take precedence over the proxy
+        let app = Router::new()
+            .route("/health", routing::get(|| async { "healthy" }))
+            .merge(proxy);
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let client = Client::new();
+        let get = |path: &str| client.get(format!("http://{}{}", addr, path));
+        let res = get("/health").send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "healthy");
+        let res = get("/_ready").send().await.unwrap();
+        assert_eq!(res.text().await.unwrap(), "ok");
+
+        for _ in 0..2 {
+            let res = get("/URL_ECHO")
+                .header("idempotency-key", "key001")
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(res.status(), 200);
+            assert_eq!(res.text().await.unwrap(), "hit 1");
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let res = get("/URL_ECHO").send().await.unwrap();
+        assert_eq!(res.status(), 400);
+    }
+
+    #[tokio::test]
+    async fn test_format_conversion_accept() {
+        // an upstream that serves JSON only
+        let upstream = Router::new()
+            .route(
+                "/json",
+                routing::get(|headers: http::HeaderMap| async move {
+                    let accept = headers
+                        .get(http::header::ACCEPT)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    if !accept.contains("application/json") {
+                        return Err((http::StatusCode::NOT_ACCEPTABLE, accept.to_string()));
+                    }
+                    Ok((
+                        [(http::header::CONTENT_TYPE, "application/json")],
+                        r#"{"id":1}"#,
+                    ))
+                }),
+            )
+            .route(
+                "/accept",
+                routing::get(|headers: http::HeaderMap| async move {
+                    let accept = headers
+                        .get(http::header::ACCEPT)
+                        .and_then(|v| v.to_str().ok())
+                        .unwrap_or_default();
+                    axum::Json(serde_json::json!({ "accept": accept }))
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let cacher = HybridCacher::new(10, 10000, Box::new(MemoryCacher::default()));
+        let proxy = ProxyBuilder::new(cacher)
+            .with_url_var("URL_JSON", &format!("http://{}/json", upstream_addr))
+            .build();
+        let req = http::Request::get("/URL_JSON")
+            .header("idempotency-key", "key001")
+            .header("accept", "application/cbor")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = proxy.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.headers().get(http::header::CONTENT_TYPE).unwrap(),
+            "application/cbor"
+        );
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        let value: ciborium::Value = ciborium::from_reader(&body[..]).unwrap();
+        assert_eq!(value, cbor!({"id" => 1}).unwrap());
+
+        // the Accept of a JSON client reaches the upstream unchanged
+        let proxy = ProxyBuilder::new(HybridCacher::new(
+            10,
+            10000,
+            Box::new(MemoryCacher::default()),
+        ))
+        .with_url_var("URL_ACCEPT", &format!("http://{}/accept", upstream_addr))
+        .build();
+        let req = http::Request::get("/URL_ACCEPT")
+            .header("idempotency-key", "key002")
+            .header("accept", "application/json, text/plain;q=0.5")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = proxy.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 200);
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        let value: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({"accept": "application/json, text/plain;q=0.5"})
+        );
+    }
+
+    #[tokio::test]
+    async fn test_error_releases_key() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let upstream = Router::new().route(
+            "/json",
+            routing::get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                (
+                    [(http::header::CONTENT_TYPE, "application/json")],
+                    "{invalid",
+                )
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await });
+
+        let cacher = HybridCacher::new(10, 10000, Box::new(MemoryCacher::default()));
+        let proxy = ProxyBuilder::new(cacher)
+            .with_url_var("URL_JSON", &format!("http://{}/json", upstream_addr))
+            .build();
+        for i in 1..=2 {
+            let req = http::Request::get("/URL_JSON")
+                .header("idempotency-key", "key001")
+                .header("x-json-mask", "id")
+                .body(axum::body::Body::empty())
+                .unwrap();
+            let res = proxy.clone().oneshot(req).await.unwrap();
+            assert_eq!(res.status(), 502);
+            // not a cache hit or a lock held by the failed request
+            assert_eq!(hits.load(Ordering::SeqCst), i);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_agent_not_allowed() {
+        let cacher = HybridCacher::new(10, 10000, Box::new(MemoryCacher::default()));
+        let proxy = ProxyBuilder::new(cacher)
+            .with_url_var("URL_ECHO", "http://127.0.0.1:1/echo")
+            .with_agents(BTreeSet::from(["rwlgt-iiaaa-aaaaa-aaaaa-cai".to_string()]))
+            .with_ic_root_key(vec![0u8; 96])
+            .build();
+
+        let req = http::Request::get("/URL_ECHO")
+            .header("idempotency-key", "key001")
+            .extension(Some(ClientIdentity {
+                agent: "rrkah-fqaaa-aaaaa-aaaaq-cai".to_string(),
+            }))
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = proxy.clone().oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 403);
+        let body = axum::body::to_bytes(res.into_body(), 1024).await.unwrap();
+        assert_eq!(
+            body,
+            "agent rrkah-fqaaa-aaaaa-aaaaq-cai is not allowed".as_bytes()
+        );
+
+        // without a client identity, a token is required
+        let req = http::Request::get("/URL_ECHO")
+            .header("idempotency-key", "key001")
+            .body(axum::body::Body::empty())
+            .unwrap();
+        let res = proxy.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), 407);
+    }
+}
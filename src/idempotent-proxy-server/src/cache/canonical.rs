@@ -0,0 +1,142 @@
+use ciborium::{into_writer, Value};
+use idempotent_proxy_types::err_string;
+use std::{collections::BTreeSet, sync::Arc};
+
+/// Canonicalizes JSON and CBOR bodies so that all ICP replicas get byte-identical responses:
+///
+/// - object keys are sorted, by code point in JSON and by encoded bytes in CBOR (RFC 8949 §4.2.1)
+/// - floats with an integral value are written as integers, other floats in their shortest form
+/// - volatile fields such as `timestamp` are removed at any depth
+#[derive(Debug, Clone, Default)]
+pub struct Canonicalizer {
+    volatile_fields: Arc<BTreeSet<String>>,
+}
+
+// Largest integer that a f64 represents exactly.
+const MAX_SAFE_INTEGER: f64 = 9007199254740991.0;
+
+impl Canonicalizer {
+    pub fn new(volatile_fields: BTreeSet<String>) -> Self {
+        Self {
+            volatile_fields: Arc::new(volatile_fields),
+        }
+    }
+
+    pub fn canonical_json(&self, value: serde_json::Value) -> Result<Vec<u8>, String> {
+        serde_json::to_vec(&self.json(value)).map_err(err_string)
+    }
+
+    pub fn canonical_cbor(&self, value: Value) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        into_writer(&self.cbor(value)?, &mut buf).map_err(err_string)?;
+        Ok(buf)
+    }
+
+    fn json(&self, value: serde_json::Value) -> serde_json::Value {
+        match value {
+            serde_json::Value::Object(obj) => {
+                let mut entries: Vec<(String, serde_json::Value)> = obj
+                    .into_iter()
+                    .filter(|(k, _)| !self.volatile_fields.contains(k))
+                    .map(|(k, v)| (k, self.json(v)))
+                    .collect();
+                // sorted explicitly, serde_json::Map keeps the insertion order with the
+                // `preserve_order` feature. UTF-8 byte order is code point order.
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                serde_json::Value::Object(entries.into_iter().collect())
+            }
+            serde_json::Value::Array(arr) => {
+                serde_json::Value::Array(arr.into_iter().map(|v| self.json(v)).collect())
+            }
+            serde_json::Value::Number(n) => match n.as_f64() {
+                Some(f) if n.is_f64() && f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER => {
+                    serde_json::Value::Number((f as i64).into())
+                }
+                _ => serde_json::Value::Number(n),
+            },
+            v => v,
+        }
+    }
+
+    fn cbor(&self, value: Value) -> Result<Value, String> {
+        match value {
+            Value::Map(list) => {
+                let mut entries = Vec::with_capacity(list.len());
+                for (k, v) in list {
+                    if k.as_text()
+                        .is_some_and(|k| self.volatile_fields.contains(k))
+                    {
+                        continue;
+                    }
+                    let k = self.cbor(k)?;
+                    let mut key = Vec::new();
+                    into_writer(&k, &mut key).map_err(err_string)?;
+                    entries.push((key, k, self.cbor(v)?));
+                }
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+                Ok(Value::Map(
+                    entries.into_iter().map(|(_, k, v)| (k, v)).collect(),
+                ))
+            }
+            Value::Array(arr) => Ok(Value::Array(
+                arr.into_iter()
+                    .map(|v| self.cbor(v))
+                    .collect::<Result<_, _>>()?,
+            )),
+            Value::Tag(tag, v) => Ok(Value::Tag(tag, Box::new(self.cbor(*v)?))),
+            Value::Float(f) if f.fract() == 0.0 && f.abs() <= MAX_SAFE_INTEGER => {
+                Ok(Value::Integer((f as i64).into()))
+            }
+            v => Ok(v),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ciborium::cbor;
+
+    #[test]
+    fn test_canonical_json() {
+        let c = Canonicalizer::new(BTreeSet::from(["timestamp".to_string()]));
+        let a: serde_json::Value = serde_json::from_str(
+            r#"{"b":1.0,"a":{"timestamp":1717000000,"y":1.50,"x":-2e2},"c":[3.0,1e300]}"#,
+        )
+        .unwrap();
+        let b: serde_json::Value = serde_json::from_str(
+            r#"{"c":[3,1e+300],"a":{"x":-200,"y":1.5,"timestamp":1717000001},"b":1}"#,
+        )
+        .unwrap();
+        let data = c.canonical_json(a).unwrap();
+        assert_eq!(data, br#"{"a":{"x":-200,"y":1.5},"b":1,"c":[3,1e300]}"#);
+        assert_eq!(data, c.canonical_json(b).unwrap());
+    }
+
+    #[test]
+    fn test_canonical_cbor() {
+        let c = Canonicalizer::new(BTreeSet::from(["requestId".to_string()]));
+        let a = cbor!({
+            "bb" => 1.0,
+            "a" => {"requestId" => "r1", "y" => 1.5},
+            10 => [3.0],
+        })
+        .unwrap();
+        let b = cbor!({
+            10 => [3],
+            "a" => {"y" => 1.5, "requestId" => "r2"},
+            "bb" => 1,
+        })
+        .unwrap();
+        let data = c.canonical_cbor(a).unwrap();
+        assert_eq!(data, c.canonical_cbor(b).unwrap());
+
+        let mut expected = Vec::new();
+        into_writer(
+            &cbor!({10 => [3], "a" => {"y" => 1.5}, "bb" => 1}).unwrap(),
+            &mut expected,
+        )
+        .unwrap();
+        assert_eq!(data, expected);
+    }
+}
@@ -0,0 +1,267 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    Aes256Gcm, Nonce,
+};
+use idempotent_proxy_types::err_string;
+use std::io::Read;
+
+use super::ResponseData;
+
+// Blob format: [BLOB_V1, flags, (nonce), payload].
+// Entries written before versioning are raw CBOR maps and never start with BLOB_V1.
+const BLOB_V1: u8 = 1;
+const FLAG_ZSTD: u8 = 1;
+const FLAG_AES_GCM: u8 = 2;
+const NONCE_LEN: usize = 12;
+
+/// Default size limit of an encoded `ResponseData`, 10 MiB.
+pub const DEFAULT_MAX_SIZE: usize = 10 * 1024 * 1024;
+
+/// Encodes `ResponseData` into the blob stored in the cacher, with optional zstd compression
+/// and AES-256-GCM encryption. The cache key is authenticated with the ciphertext,
+/// so an encrypted entry can not be moved to another key.
+///
+/// With an encryption key, unencrypted blobs are rejected unless plaintext reads are
+/// allowed to migrate a cache written without encryption.
+pub struct ResponseCodec {
+    zstd_level: Option<i32>,
+    cipher: Option<Aes256Gcm>,
+    allow_plaintext: bool,
+    max_size: usize,
+}
+
+impl Default for ResponseCodec {
+    fn default() -> Self {
+        Self {
+            zstd_level: None,
+            cipher: None,
+            allow_plaintext: false,
+            max_size: DEFAULT_MAX_SIZE,
+        }
+    }
+}
+
+impl ResponseCodec {
+    pub fn new(zstd_level: Option<i32>, encryption_key: Option<&[u8]>) -> Result<Self, String> {
+        let cipher = match encryption_key {
+            Some(key) => Some(
+                Aes256Gcm::new_from_slice(key)
+                    .map_err(|_| "invalid AES-256-GCM key, 32 bytes required".to_string())?,
+            ),
+            None => None,
+        };
+        Ok(Self {
+            zstd_level,
+            cipher,
+            ..Default::default()
+        })
+    }
+
+    /// Accepts unencrypted blobs when an encryption key is set, during a migration.
+    pub fn with_plaintext_reads(mut self, allow: bool) -> Self {
+        self.allow_plaintext = allow;
+        self
+    }
+
+    /// Sets the size limit of an encoded `ResponseData`, larger responses are not cached
+    /// and cached blobs are not decompressed beyond it.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    pub fn encode(&self, key: &str, rd: &ResponseData) -> Result<Vec<u8>, String> {
+        let mut data = rd.to_bytes()?;
+        if data.len() > self.max_size {
+            return Err(format!(
+                "response of {} bytes exceeds the cache limit of {} bytes",
+                data.len(),
+                self.max_size
+            ));
+        }
+        if self.zstd_level.is_none() && self.cipher.is_none() {
+            return Ok(data);
+        }
+
+        let mut flags = 0u8;
+        if let Some(level) = self.zstd_level {
+            data = zstd::bulk::compress(&data, level).map_err(err_string)?;
+            flags |= FLAG_ZSTD;
+        }
+
+        let mut buf = Vec::with_capacity(data.len() + 2 + NONCE_LEN + 16);
+        match &self.cipher {
+            Some(cipher) => {
+                flags |= FLAG_AES_GCM;
+                let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+                let data = cipher
+                    .encrypt(
+                        &nonce,
+                        Payload {
+                            msg: &data,
+                            aad: key.as_bytes(),
+                        },
+                    )
+                    .map_err(err_string)?;
+                buf.extend_from_slice(&[BLOB_V1, flags]);
+                buf.extend_from_slice(&nonce);
+                buf.extend_from_slice(&data);
+            }
+            None => {
+                buf.extend_from_slice(&[BLOB_V1, flags]);
+                buf.extend_from_slice(&data);
+            }
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(&self, key: &str, data: &[u8]) -> Result<ResponseData, String> {
+        let plaintext_ok = self.cipher.is_none() || self.allow_plaintext;
+        if data.first() != Some(&BLOB_V1) {
+            if !plaintext_ok {
+                return Err("cached blob is not encrypted".to_string());
+            }
+            return ResponseData::try_from(data);
+        }
+        if data.len() < 2 {
+            return Err("invalid cached blob".to_string());
+        }
+
+        let flags = data[1];
+        if flags & FLAG_AES_GCM == 0 && !plaintext_ok {
+            return Err("cached blob is not encrypted".to_string());
+        }
+        let mut data = data[2..].to_vec();
+        if flags & FLAG_AES_GCM != 0 {
+            let cipher = self
+                .cipher
+                .as_ref()
+                .ok_or("cached blob is encrypted but no encryption key is configured")?;
+            if data.len() < NONCE_LEN {
+                return Err("invalid cached blob".to_string());
+            }
+            data = cipher
+                .decrypt(
+                    Nonce::from_slice(&data[..NONCE_LEN]),
+                    Payload {
+                        msg: &data[NONCE_LEN..],
+                        aad: key.as_bytes(),
+                    },
+                )
+                .map_err(|_| "failed to decrypt cached blob".to_string())?;
+        }
+        if flags & FLAG_ZSTD != 0 {
+            let mut buf = Vec::new();
+            zstd::stream::read::Decoder::new(&data[..])
+                .map_err(err_string)?
+                .take(self.max_size as u64 + 1)
+                .read_to_end(&mut buf)
+                .map_err(err_string)?;
+            if buf.len() > self.max_size {
+                return Err(format!(
+                    "cached blob exceeds the cache limit of {} bytes",
+                    self.max_size
+                ));
+            }
+            data = buf;
+        }
+        ResponseData::try_from(&data[..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_response_codec() {
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json".to_string();
+        rd.body
+            .extend_from_slice(&br#"{"result":"0x0000"}"#.repeat(100));
+        let legacy = rd.to_bytes().unwrap();
+
+        let key = [7u8; 32];
+        let codecs = [
+            ResponseCodec::default(),
+            ResponseCodec::new(Some(3), None).unwrap(),
+            ResponseCodec::new(None, Some(&key)).unwrap(),
+            ResponseCodec::new(Some(3), Some(&key)).unwrap(),
+        ];
+        for codec in &codecs {
+            let data = codec.encode("k1", &rd).unwrap();
+            assert_eq!(codec.decode("k1", &data).unwrap(), rd);
+        }
+        // entries written before versioning still decode without encryption
+        assert_eq!(codecs[0].decode("k1", &legacy).unwrap(), rd);
+        assert_eq!(codecs[1].decode("k1", &legacy).unwrap(), rd);
+
+        let data = codecs[1].encode("k1", &rd).unwrap();
+        assert_eq!(data[..2], [BLOB_V1, FLAG_ZSTD]);
+        assert!(data.len() < legacy.len() / 4);
+
+        let data = codecs[3].encode("k1", &rd).unwrap();
+        assert_eq!(data[..2], [BLOB_V1, FLAG_ZSTD | FLAG_AES_GCM]);
+        assert!(codecs[3].decode("k2", &data).is_err());
+        assert!(codecs[0].decode("k1", &data).is_err());
+        let other = ResponseCodec::new(Some(3), Some(&[8u8; 32])).unwrap();
+        assert!(other.decode("k1", &data).is_err());
+
+        assert!(ResponseCodec::new(None, Some(&[0u8; 16])).is_err());
+    }
+
+    #[test]
+    fn test_response_codec_plaintext() {
+        let mut rd = ResponseData::new(200);
+        rd.body.extend_from_slice(b"secret");
+        let legacy = rd.to_bytes().unwrap();
+        let compressed = ResponseCodec::new(Some(3), None)
+            .unwrap()
+            .encode("k1", &rd)
+            .unwrap();
+
+        let key = [7u8; 32];
+        let codec = ResponseCodec::new(Some(3), Some(&key)).unwrap();
+        for data in [&legacy, &compressed] {
+            assert_eq!(
+                codec.decode("k1", data).unwrap_err(),
+                "cached blob is not encrypted"
+            );
+        }
+
+        let codec = codec.with_plaintext_reads(true);
+        for data in [&legacy, &compressed] {
+            assert_eq!(codec.decode("k1", data).unwrap(), rd);
+        }
+        let data = codec.encode("k1", &rd).unwrap();
+        assert_eq!(data[..2], [BLOB_V1, FLAG_ZSTD | FLAG_AES_GCM]);
+    }
+
+    #[test]
+    fn test_response_codec_max_size() {
+        let mut rd = ResponseData::new(200);
+        rd.body.extend_from_slice(&[0u8; 10000]);
+        let size = rd.to_bytes().unwrap().len();
+
+        let codec = ResponseCodec::new(Some(3), None)
+            .unwrap()
+            .with_max_size(size);
+        let data = codec.encode("k1", &rd).unwrap();
+        assert_eq!(codec.decode("k1", &data).unwrap(), rd);
+
+        // a small blob that decompresses beyond the limit
+        let codec = codec.with_max_size(size - 1);
+        assert_eq!(
+            codec.decode("k1", &data).unwrap_err(),
+            format!("cached blob exceeds the cache limit of {} bytes", size - 1)
+        );
+        assert_eq!(
+            codec.encode("k1", &rd).unwrap_err(),
+            format!(
+                "response of {} bytes exceeds the cache limit of {} bytes",
+                size,
+                size - 1
+            )
+        );
+    }
+}
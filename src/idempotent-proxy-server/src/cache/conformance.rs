@@ -0,0 +1,236 @@
+//! A conformance suite of the `Cacher` contract, run against every backend by its tests.
+
+use futures::future::join_all;
+use idempotent_proxy_types::clock::ManualClock;
+use tokio::time::{sleep, Duration};
+
+use super::{CacheError, Cacher, LockToken};
+
+const TTL: u64 = 200;
+const POLL_INTERVAL: u64 = 10;
+
+/// How the suite lets the time pass: by moving the clock of the backend,
+/// or by sleeping for backends that expire keys on their own, such as Redis.
+pub enum TestTime {
+    Manual(ManualClock),
+    Real,
+}
+
+impl TestTime {
+    async fn advance(&self, ms: u64) {
+        match self {
+            Self::Manual(clock) => clock.advance(ms),
+            // some slack for the round-trips to the backend
+            Self::Real => sleep(Duration::from_millis(ms + 50)).await,
+        }
+    }
+}
+
+pub async fn run(cacher: &dyn Cacher, time: &TestTime) {
+    cacher.ping().await.unwrap();
+    obtain_race(cacher).await;
+    set_without_obtain(cacher).await;
+    lock_expiry(cacher, time).await;
+    value_expiry(cacher, time).await;
+    delete(cacher).await;
+    polling(cacher).await;
+    lock_fencing(cacher, time).await;
+    large_values(cacher).await;
+}
+
+fn not_stored(res: &Result<bool, CacheError>) -> bool {
+    // Redis reports a failed conditional set as false, other backends as errors.
+    matches!(
+        res,
+        Ok(false) | Err(CacheError::NotObtained) | Err(CacheError::Expired)
+    )
+}
+
+async fn obtain(cacher: &dyn Cacher, key: &str, ttl: u64) -> LockToken {
+    match cacher.obtain(key, ttl).await {
+        Ok(Some(token)) => token,
+        res => panic!("obtain {}: {:?}", key, res),
+    }
+}
+
+async fn obtain_race(cacher: &dyn Cacher) {
+    let key = "conformance:race";
+    let res = join_all((0..16).map(|_| cacher.obtain(key, 10000))).await;
+    let tokens: Vec<LockToken> = res.iter().filter_map(|r| r.clone().ok()?).collect();
+    assert_eq!(tokens.len(), 1, "obtain race: {:?}", res);
+    assert!(res.iter().all(|r| r.is_ok()), "obtain race: {:?}", res);
+
+    assert_eq!(cacher.obtain(key, 10000).await.unwrap(), None);
+    assert!(cacher
+        .set(key, tokens[0], vec![1, 2, 3], 10000)
+        .await
+        .unwrap());
+    let res = join_all((0..16).map(|_| cacher.obtain(key, 10000))).await;
+    assert!(
+        res.iter().all(|r| matches!(r, Ok(None))),
+        "obtain completed: {:?}",
+        res
+    );
+    cacher.del(key).await.unwrap();
+}
+
+async fn set_without_obtain(cacher: &dyn Cacher) {
+    let key = "conformance:set-without-obtain";
+    let res = cacher.set(key, 1, vec![1, 2, 3], 10000).await;
+    assert!(not_stored(&res), "set without obtain: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+
+    // with another token than the lock's
+    let token = obtain(cacher, key, 10000).await;
+    let res = cacher
+        .set(key, token.wrapping_add(1), vec![1, 2, 3], 10000)
+        .await;
+    assert!(not_stored(&res), "set with another token: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+
+    // once the value is set
+    assert!(cacher.set(key, token, vec![1, 2, 3], 10000).await.unwrap());
+    let res = cacher.set(key, token, vec![4, 5, 6], 10000).await;
+    assert!(not_stored(&res), "set again: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), Some(vec![1, 2, 3]));
+    cacher.del(key).await.unwrap();
+}
+
+async fn lock_expiry(cacher: &dyn Cacher, time: &TestTime) {
+    let key = "conformance:lock-expiry";
+    let token = obtain(cacher, key, TTL).await;
+    assert_eq!(cacher.obtain(key, TTL).await.unwrap(), None);
+    time.advance(TTL).await;
+
+    let res = cacher.set(key, token, vec![1, 2, 3], 10000).await;
+    assert!(not_stored(&res), "set after lock expiry: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+    obtain(cacher, key, 10000).await;
+    cacher.del(key).await.unwrap();
+}
+
+async fn value_expiry(cacher: &dyn Cacher, time: &TestTime) {
+    let key = "conformance:value-expiry";
+    let token = obtain(cacher, key, 10000).await;
+    // set renews the TTL
+    assert!(cacher
+        .set(key, token, vec![1, 2, 3], TTL * 2)
+        .await
+        .unwrap());
+    time.advance(TTL / 2).await;
+    assert_eq!(cacher.get(key).await.unwrap(), Some(vec![1, 2, 3]));
+    assert_eq!(cacher.obtain(key, TTL).await.unwrap(), None);
+
+    time.advance(TTL * 2).await;
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+    obtain(cacher, key, 10000).await;
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+    cacher.del(key).await.unwrap();
+}
+
+async fn delete(cacher: &dyn Cacher) {
+    let key = "conformance:delete";
+    // missing
+    cacher.del(key).await.unwrap();
+
+    // obtained
+    let token = obtain(cacher, key, 10000).await;
+    cacher.del(key).await.unwrap();
+    let res = cacher.set(key, token, vec![1, 2, 3], 10000).await;
+    assert!(not_stored(&res), "set after delete: {:?}", res);
+
+    // completed
+    let token = obtain(cacher, key, 10000).await;
+    assert!(cacher.set(key, token, vec![1, 2, 3], 10000).await.unwrap());
+    cacher.del(key).await.unwrap();
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+    assert_eq!(
+        cacher.polling_get(key, POLL_INTERVAL, 2).await,
+        Err(CacheError::NotObtained)
+    );
+    obtain(cacher, key, 10000).await;
+    cacher.del(key).await.unwrap();
+}
+
+async fn polling(cacher: &dyn Cacher) {
+    let key = "conformance:polling";
+    assert_eq!(
+        cacher.polling_get(key, POLL_INTERVAL, 2).await,
+        Err(CacheError::NotObtained)
+    );
+    let token = obtain(cacher, key, 10000).await;
+    assert_eq!(
+        cacher.polling_get(key, POLL_INTERVAL, 3).await,
+        Err(CacheError::Timeout)
+    );
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+
+    // waiters get the value once it is set
+    let set = async {
+        sleep(Duration::from_millis(POLL_INTERVAL * 5)).await;
+        cacher.set(key, token, vec![1, 2, 3], 10000).await
+    };
+    let (res, v1, v2) = futures::join!(
+        set,
+        cacher.polling_get(key, POLL_INTERVAL, 100),
+        cacher.polling_get(key, POLL_INTERVAL, 100)
+    );
+    assert!(res.unwrap());
+    assert_eq!(v1.unwrap(), vec![1, 2, 3]);
+    assert_eq!(v2.unwrap(), vec![1, 2, 3]);
+    assert_eq!(
+        cacher.polling_get(key, POLL_INTERVAL, 1).await.unwrap(),
+        vec![1, 2, 3]
+    );
+    cacher.del(key).await.unwrap();
+}
+
+async fn lock_fencing(cacher: &dyn Cacher, time: &TestTime) {
+    let key = "conformance:fencing";
+    // a live lock fences out other requests until it is deleted
+    obtain(cacher, key, 10000).await;
+    let res = join_all((0..8).map(|_| cacher.obtain(key, 10000))).await;
+    assert!(
+        res.iter().all(|r| matches!(r, Ok(None))),
+        "obtain locked: {:?}",
+        res
+    );
+    cacher.del(key).await.unwrap();
+
+    // an expired lock is taken over by exactly one request
+    let stale = obtain(cacher, key, TTL).await;
+    time.advance(TTL).await;
+    let res = join_all((0..8).map(|_| cacher.obtain(key, 10000))).await;
+    let tokens: Vec<LockToken> = res.iter().filter_map(|r| r.clone().ok()?).collect();
+    assert_eq!(tokens.len(), 1, "obtain expired lock: {:?}", res);
+    assert_ne!(tokens[0], stale);
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+
+    // the stale holder can not set the value, before or after the new holder
+    let res = cacher.set(key, stale, vec![1, 2, 3], 10000).await;
+    assert!(not_stored(&res), "stale set before takeover set: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), None);
+    assert!(cacher
+        .set(key, tokens[0], vec![4, 5, 6], 10000)
+        .await
+        .unwrap());
+    let res = cacher.set(key, stale, vec![1, 2, 3], 10000).await;
+    assert!(not_stored(&res), "stale set after takeover set: {:?}", res);
+    assert_eq!(cacher.get(key).await.unwrap(), Some(vec![4, 5, 6]));
+    cacher.del(key).await.unwrap();
+}
+
+async fn large_values(cacher: &dyn Cacher) {
+    for (i, size) in [2, 64 * 1024, 4 * 1024 * 1024].into_iter().enumerate() {
+        let key = format!("conformance:large-{}", i);
+        let val: Vec<u8> = (0..size).map(|i| (i % 251) as u8 + 1).collect();
+        let token = obtain(cacher, &key, 10000).await;
+        assert!(cacher.set(&key, token, val.clone(), 10000).await.unwrap());
+        assert_eq!(cacher.get(&key).await.unwrap().as_ref(), Some(&val));
+        assert_eq!(
+            cacher.polling_get(&key, POLL_INTERVAL, 1).await.unwrap(),
+            val
+        );
+        cacher.del(&key).await.unwrap();
+    }
+}
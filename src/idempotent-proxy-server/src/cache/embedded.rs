@@ -0,0 +1,393 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use async_trait::async_trait;
+use idempotent_proxy_types::{
+    clock::{Clock, SystemClock},
+    err_string,
+};
+use sled::{
+    transaction::{
+        ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+        TransactionalTree,
+    },
+    Transactional, Tree,
+};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+use super::{CacheError, Cacher, LockToken};
+
+/// A cacher on the embedded [sled](https://docs.rs/sled) database, for single-node
+/// deployments that should keep the cache across restarts without running Redis.
+///
+/// Values are stored as `expire_at (u64 BE) || value`, and locks that are obtained but
+/// not set yet as `expire_at || 0x00 || token (u64 BE)`. An index tree keyed by `expire_at || key` is scanned by `compact`
+/// to remove the expired entries. Both trees are updated in one transaction.
+#[derive(Clone)]
+pub struct SledCacher {
+    kv: Tree,
+    expires: Tree,
+    clock: Arc<dyn Clock>,
+}
+
+impl SledCacher {
+    pub fn open(path: &str) -> Result<Self, String> {
+        Self::with_config(sled::Config::new().path(path))
+    }
+
+    pub fn with_config(config: sled::Config) -> Result<Self, String> {
+        let db = config.open().map_err(err_string)?;
+        let kv = db.open_tree("kv").map_err(err_string)?;
+        let expires = db.open_tree("expires").map_err(err_string)?;
+        Ok(Self {
+            kv,
+            expires,
+            clock: Arc::new(SystemClock),
+        })
+    }
+
+    /// Uses the clock for expirations instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Removes the expired entries, returns the number of removed entries.
+    pub fn compact(&self) -> Result<usize, String> {
+        let now = self.clock.now_ms();
+        let mut removed = 0;
+        for item in self.expires.range(..now.to_be_bytes().as_slice()) {
+            let (index, _) = item.map_err(err_string)?;
+            let key = &index[8..];
+            let res = self.transaction(|kv, expires| {
+                expires.remove(&index)?;
+                // the entry may have been obtained again with a new expiration
+                match kv.get(key)? {
+                    Some(current) if current[..8] == index[..8] => {
+                        kv.remove(key)?;
+                        Ok(true)
+                    }
+                    _ => Ok(false),
+                }
+            });
+            if res.map_err(err_string)? {
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Spawns a task that compacts the database at every `interval`.
+    pub fn spawn_compaction(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let cacher = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                match cacher.compact() {
+                    Ok(n) if n > 0 => {
+                        log::info!(target: "cacher", "sled compaction removed {} entries", n);
+                    }
+                    Ok(_) => {}
+                    Err(err) => {
+                        log::error!(target: "cacher", "sled compaction failed: {}", err);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Flushes all dirty data to disk, sled also flushes in the background every 500ms.
+    pub async fn flush(&self) -> Result<(), String> {
+        self.kv.flush_async().await.map(|_| ()).map_err(err_string)
+    }
+
+    // Runs `f` on the kv and index trees in one transaction, retried on conflicts.
+    fn transaction<T>(
+        &self,
+        f: impl Fn(
+            &TransactionalTree,
+            &TransactionalTree,
+        ) -> ConflictableTransactionResult<T, CacheError>,
+    ) -> Result<T, CacheError> {
+        (&self.kv, &self.expires)
+            .transaction(|(kv, expires)| f(kv, expires))
+            .map_err(|err| match err {
+                TransactionError::Abort(err) => err,
+                TransactionError::Storage(err) => CacheError::backend(err),
+            })
+    }
+}
+
+// Replaces the entry of the key and its index entry.
+fn put(
+    kv: &TransactionalTree,
+    expires: &TransactionalTree,
+    key: &str,
+    old: Option<&[u8]>,
+    expire_at: u64,
+    val: &[u8],
+) -> ConflictableTransactionResult<(), CacheError> {
+    if let Some(old) = old {
+        expires.remove(index_key(&old[..8], key))?;
+    }
+    let mut data = Vec::with_capacity(8 + val.len());
+    data.extend_from_slice(&expire_at.to_be_bytes());
+    data.extend_from_slice(val);
+    kv.insert(key, data)?;
+    expires.insert(index_key(&expire_at.to_be_bytes(), key), &[])?;
+    Ok(())
+}
+
+fn index_key(expire_at: &[u8], key: &str) -> Vec<u8> {
+    let mut index = Vec::with_capacity(8 + key.len());
+    index.extend_from_slice(expire_at);
+    index.extend_from_slice(key.as_bytes());
+    index
+}
+
+// Returns the value of a completed entry, None for a lock.
+fn value(data: &[u8]) -> Option<&[u8]> {
+    data.get(8..).filter(|v| v.first().is_some_and(|b| *b != 0))
+}
+
+fn lock_value(token: LockToken) -> [u8; 9] {
+    let mut buf = [0u8; 9];
+    buf[1..].copy_from_slice(&token.to_be_bytes());
+    buf
+}
+
+fn expire_at(data: &[u8]) -> u64 {
+    let mut buf = [0u8; 8];
+    buf.copy_from_slice(&data[..8]);
+    u64::from_be_bytes(buf)
+}
+
+#[async_trait]
+impl Cacher for SledCacher {
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<Option<LockToken>, CacheError> {
+        let now = self.clock.now_ms();
+        // random, as locks outlive restarts
+        let token = OsRng.next_u64();
+        self.transaction(|kv, expires| {
+            let current = kv.get(key)?;
+            if let Some(current) = &current {
+                if expire_at(current) > now {
+                    return Ok(None);
+                }
+            }
+            put(
+                kv,
+                expires,
+                key,
+                current.as_deref(),
+                now + ttl,
+                &lock_value(token),
+            )?;
+            Ok(Some(token))
+        })
+    }
+
+    async fn polling_get(
+        &self,
+        key: &str,
+        poll_interval: u64,
+        mut counter: u64,
+    ) -> Result<Vec<u8>, CacheError> {
+        while counter > 0 {
+            match self.kv.get(key).map_err(CacheError::backend)? {
+                None => return Err(CacheError::NotObtained),
+                Some(data) => {
+                    if let Some(val) = value(&data) {
+                        return Ok(val.to_vec());
+                    }
+                }
+            }
+
+            counter -= 1;
+            sleep(Duration::from_millis(poll_interval)).await;
+        }
+
+        Err(CacheError::Timeout)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        match self.kv.get(key).map_err(CacheError::backend)? {
+            Some(data) if expire_at(&data) > self.clock.now_ms() => {
+                Ok(value(&data).map(|v| v.to_vec()))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        token: LockToken,
+        val: Vec<u8>,
+        ttl: u64,
+    ) -> Result<bool, CacheError> {
+        let now = self.clock.now_ms();
+        self.transaction(|kv, expires| {
+            let current = match kv.get(key)? {
+                Some(current) => current,
+                None => return Err(ConflictableTransactionError::Abort(CacheError::NotObtained)),
+            };
+            if expire_at(&current) <= now {
+                return Err(ConflictableTransactionError::Abort(CacheError::Expired));
+            }
+            // taken over by another request, or already set
+            if current[8..] != lock_value(token) {
+                return Ok(false);
+            }
+            put(kv, expires, key, Some(&current), now + ttl, &val)?;
+            Ok(true)
+        })
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        self.transaction(|kv, expires| {
+            if let Some(data) = kv.remove(key)? {
+                expires.remove(index_key(&data[..8], key))?;
+            }
+            Ok(())
+        })
+    }
+
+    async fn ping(&self) -> Result<(), CacheError> {
+        self.kv.first().map(|_| ()).map_err(CacheError::backend)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::conformance;
+    use idempotent_proxy_types::{clock::ManualClock, unix_ms};
+
+    #[tokio::test]
+    async fn sled_cacher() {
+        let mc = SledCacher::with_config(sled::Config::new().temporary(true)).unwrap();
+
+        let token = mc.obtain("key1", 100).await.unwrap().unwrap();
+        assert_eq!(mc.obtain("key1", 100).await.unwrap(), None);
+        assert!(mc.polling_get("key1", 10, 2).await.is_err());
+        assert!(mc.set("key", token, vec![1, 2, 3, 4], 100).await.is_err());
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_ok());
+        assert_eq!(mc.obtain("key1", 100).await.unwrap(), None);
+        assert_eq!(
+            mc.polling_get("key1", 10, 2).await.unwrap(),
+            vec![1, 2, 3, 4]
+        );
+
+        assert_eq!(mc.get("key1").await.unwrap(), Some(vec![1, 2, 3, 4]));
+        assert_eq!(mc.get("key").await.unwrap(), None);
+
+        assert!(mc.del("key").await.is_ok());
+        assert!(mc.del("key1").await.is_ok());
+        assert_eq!(mc.get("key1").await.unwrap(), None);
+        assert!(mc.polling_get("key1", 10, 2).await.is_err());
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_err());
+        let token = mc.obtain("key1", 100).await.unwrap().unwrap();
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_ok());
+
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(mc.get("key1").await.unwrap(), None);
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_err());
+        assert!(mc.obtain("key1", 100).await.unwrap().is_some());
+        assert_eq!(mc.kv.len(), 1);
+        assert_eq!(mc.expires.len(), 1);
+        assert_eq!(mc.compact().unwrap(), 0);
+
+        sleep(Duration::from_millis(200)).await;
+        let res = futures::try_join!(
+            mc.obtain("key1", 100),
+            mc.obtain("key1", 100),
+            mc.obtain("key1", 100),
+        )
+        .unwrap();
+        match res {
+            (Some(_), None, None) | (None, Some(_), None) | (None, None, Some(_)) => {}
+            _ => panic!("unexpected result"),
+        }
+        assert_eq!(mc.expires.len(), 1);
+
+        assert!(mc.obtain("key2", 100).await.unwrap().is_some());
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(mc.compact().unwrap(), 2);
+        assert!(mc.kv.is_empty());
+        assert!(mc.expires.is_empty());
+    }
+
+    #[tokio::test]
+    async fn sled_cacher_conformance() {
+        let clock = ManualClock::new(unix_ms());
+        let mc = SledCacher::with_config(sled::Config::new().temporary(true))
+            .unwrap()
+            .with_clock(Arc::new(clock.clone()));
+        conformance::run(&mc, &conformance::TestTime::Manual(clock)).await;
+        assert!(mc.kv.is_empty());
+        assert!(mc.expires.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn sled_cacher_index() {
+        let mc = SledCacher::with_config(sled::Config::new().temporary(true)).unwrap();
+        let tasks: Vec<_> = (0..8)
+            .map(|i| {
+                let mc = mc.clone();
+                tokio::spawn(async move {
+                    for r in 0..200 {
+                        let key = format!("key{}", (i + r) % 4);
+                        if let Some(token) = mc.obtain(&key, 10000).await.unwrap() {
+                            let _ = mc.set(&key, token, vec![1, 2, 3], 10000).await;
+                        } else if r % 3 == 0 {
+                            mc.del(&key).await.unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // every entry has exactly one index entry
+        assert_eq!(mc.kv.len(), mc.expires.len());
+        for item in mc.kv.iter() {
+            let (key, data) = item.unwrap();
+            let key = std::str::from_utf8(&key).unwrap();
+            assert!(mc.expires.contains_key(index_key(&data[..8], key)).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn sled_cacher_reopen() {
+        let path = std::env::temp_dir().join(format!("idempotent-proxy-sled-{}", unix_ms()));
+        let path = path.to_str().unwrap();
+        {
+            let mc = SledCacher::open(path).unwrap();
+            let token = mc.obtain("key1", 10000).await.unwrap().unwrap();
+            assert!(mc
+                .set("key1", token, vec![1, 2, 3, 4], 10000)
+                .await
+                .unwrap());
+            mc.flush().await.unwrap();
+        }
+
+        // the sled flusher thread may still hold the file lock for a moment
+        let mut retries = 0;
+        let mc = loop {
+            match SledCacher::open(path) {
+                Ok(mc) => break mc,
+                Err(err) if retries < 50 && err.contains("could not acquire lock") => {
+                    retries += 1;
+                    sleep(Duration::from_millis(20)).await;
+                }
+                Err(err) => panic!("{}", err),
+            }
+        };
+        assert_eq!(mc.obtain("key1", 10000).await.unwrap(), None);
+        assert_eq!(mc.get("key1").await.unwrap(), Some(vec![1, 2, 3, 4]));
+        drop(mc);
+        let _ = std::fs::remove_dir_all(path);
+    }
+}
@@ -0,0 +1,131 @@
+use idempotent_proxy_types::clock::{Clock, SystemClock};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex, PoisonError},
+};
+
+struct Entry {
+    expire_at: u64,
+    tick: u64,
+    value: Vec<u8>,
+}
+
+#[derive(Default)]
+struct Lru {
+    kv: HashMap<String, Entry>,
+    order: BTreeMap<u64, String>, // by last access
+    tick: u64,
+}
+
+/// A small in-process LRU cache of completed values with a short TTL, in front of
+/// a shared cacher backend. Completed values never change, so entries are only
+/// dropped on expiry, eviction or explicit deletes.
+pub struct L1Cache {
+    lru: Mutex<Lru>,
+    capacity: usize,
+    ttl: u64,
+    clock: Arc<dyn Clock>,
+}
+
+impl L1Cache {
+    pub fn new(capacity: usize, ttl_ms: u64) -> Self {
+        Self {
+            lru: Mutex::new(Lru::default()),
+            capacity: capacity.max(1),
+            ttl: ttl_ms,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Uses the clock for expirations instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let mut lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        let lru = &mut *lru;
+        let entry = lru.kv.get_mut(key)?;
+        if entry.expire_at <= self.clock.now_ms() {
+            lru.order.remove(&entry.tick);
+            lru.kv.remove(key);
+            return None;
+        }
+
+        lru.tick += 1;
+        lru.order.remove(&entry.tick);
+        entry.tick = lru.tick;
+        lru.order.insert(entry.tick, key.to_string());
+        Some(entry.value.clone())
+    }
+
+    /// Puts a completed value, kept for the shorter of the L1 TTL and `ttl_ms`.
+    pub fn put(&self, key: &str, value: Vec<u8>, ttl_ms: u64) {
+        let mut lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = lru.kv.remove(key) {
+            lru.order.remove(&entry.tick);
+        }
+        while lru.kv.len() >= self.capacity {
+            match lru.order.pop_first() {
+                Some((_, k)) => {
+                    lru.kv.remove(&k);
+                }
+                None => break,
+            }
+        }
+
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key.to_string());
+        lru.kv.insert(
+            key.to_string(),
+            Entry {
+                expire_at: self.clock.now_ms() + self.ttl.min(ttl_ms),
+                tick,
+                value,
+            },
+        );
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut lru = self.lru.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = lru.kv.remove(key) {
+            lru.order.remove(&entry.tick);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use idempotent_proxy_types::clock::ManualClock;
+
+    #[test]
+    fn test_l1_cache() {
+        let clock = ManualClock::new(1_000_000);
+        let l1 = L1Cache::new(2, 100).with_clock(Arc::new(clock.clone()));
+        l1.put("k1", vec![1], 1000);
+        l1.put("k2", vec![2], 1000);
+        assert_eq!(l1.get("k1"), Some(vec![1]));
+        // k2 is the least recently used
+        l1.put("k3", vec![3], 1000);
+        assert_eq!(l1.get("k2"), None);
+        assert_eq!(l1.get("k1"), Some(vec![1]));
+        assert_eq!(l1.get("k3"), Some(vec![3]));
+
+        l1.remove("k1");
+        assert_eq!(l1.get("k1"), None);
+        l1.put("k1", vec![1], 0);
+        assert_eq!(l1.get("k1"), None);
+
+        // the L1 TTL caps the TTL of the entries
+        clock.advance(99);
+        assert_eq!(l1.get("k3"), Some(vec![3]));
+        clock.advance(1);
+        assert_eq!(l1.get("k3"), None);
+        let lru = l1.lru.lock().unwrap();
+        assert!(lru.kv.is_empty());
+        assert!(lru.order.is_empty());
+    }
+}
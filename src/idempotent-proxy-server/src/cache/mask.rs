@@ -0,0 +1,574 @@
+use ciborium::Value;
+use std::str::FromStr;
+
+/// The maximum nesting depth of a mask, counting both path segments and sub-selections.
+pub const MAX_DEPTH: usize = 32;
+/// The maximum number of keys in a mask.
+pub const MAX_KEYS: usize = 256;
+
+/// A parsed `x-json-mask`, following the JSON Mask syntax:
+///
+/// - `a,b,c` comma-separated list selects multiple keys
+/// - `a/b/c` path selects a key from a parent
+/// - `a(b,c)` sub-selection selects keys from a parent
+/// - `a/*/c` the star `*` wildcard selects all keys, or all elements of an array
+/// - `\` escapes the next character, such as `a\/b` for the key `a/b`
+///
+/// Arrays are projected element-wise, so `items/id` and `items/*/id` select `id` from every
+/// element of `items`. A scalar element that a mask cannot select from becomes `null`, so the
+/// indexes of the other elements are kept.
+/// An empty mask selects everything. A mask deeper than [`MAX_DEPTH`] or with more than
+/// [`MAX_KEYS`] keys is rejected.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct JsonMask {
+    props: Vec<(String, Selection)>,
+    wildcard: Option<Box<Selection>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Selection {
+    All,
+    Mask(JsonMask),
+}
+
+impl Selection {
+    fn merge(&mut self, other: Selection) {
+        match (self, other) {
+            (Selection::All, _) => {}
+            (this, Selection::All) => *this = Selection::All,
+            (Selection::Mask(this), Selection::Mask(other)) => this.merge(other),
+        }
+    }
+}
+
+impl JsonMask {
+    pub fn parse(mask: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: mask.chars().collect(),
+            pos: 0,
+            depth: 0,
+            keys: 0,
+        };
+        let rt = parser.list()?;
+        if let Some(c) = parser.peek() {
+            return Err(format!(
+                "invalid json mask: unexpected {:?} at position {}",
+                c, parser.pos
+            ));
+        }
+        Ok(rt)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.props.is_empty() && self.wildcard.is_none()
+    }
+
+    fn insert(&mut self, key: String, sel: Selection) {
+        if key == "*" {
+            match &mut self.wildcard {
+                Some(w) => w.merge(sel),
+                None => self.wildcard = Some(Box::new(sel)),
+            }
+        } else {
+            match self.props.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, s)) => s.merge(sel),
+                None => self.props.push((key, sel)),
+            }
+        }
+    }
+
+    fn merge(&mut self, other: JsonMask) {
+        for (k, s) in other.props {
+            self.insert(k, s);
+        }
+        if let Some(w) = other.wildcard {
+            self.insert("*".to_string(), *w);
+        }
+    }
+
+    // A named key takes precedence over the wildcard.
+    fn get(&self, key: &str) -> Option<&Selection> {
+        self.props
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, s)| s)
+            .or(self.wildcard.as_deref())
+    }
+
+    // The selection of array elements: the wildcard selects the elements, merged with
+    // the named keys. `None` if there is no wildcard, the mask applies to the elements.
+    fn element_selection(&self) -> Option<Selection> {
+        let wildcard = self.wildcard.as_deref()?.clone();
+        if self.props.is_empty() {
+            return Some(wildcard);
+        }
+        let mut sel = Selection::Mask(JsonMask {
+            props: self.props.clone(),
+            wildcard: None,
+        });
+        sel.merge(wildcard);
+        Some(sel)
+    }
+
+    /// Applies the mask to a JSON response body. Objects are masked, arrays such as
+    /// JSON-RPC batch responses are masked element-wise, and scalars pass through.
+    pub fn mask_json(&self, value: serde_json::Value) -> serde_json::Value {
+        match value {
+            v @ (serde_json::Value::Object(_) | serde_json::Value::Array(_)) => {
+                self.apply_json(v).unwrap_or_default()
+            }
+            v => v,
+        }
+    }
+
+    /// Applies the mask to a CBOR response body, in the same way as `mask_json`.
+    pub fn mask_cbor(&self, value: Value) -> Value {
+        match value {
+            Value::Tag(tag, v) => Value::Tag(tag, Box::new(self.mask_cbor(*v))),
+            v @ (Value::Map(_) | Value::Array(_)) => self.apply_cbor(v).unwrap_or(Value::Null),
+            v => v,
+        }
+    }
+
+    /// Applies the mask to a JSON value. Returns `None` if nothing is selected from a scalar.
+    pub fn apply_json(&self, value: serde_json::Value) -> Option<serde_json::Value> {
+        if self.is_empty() {
+            return Some(value);
+        }
+
+        match value {
+            serde_json::Value::Object(obj) => {
+                let mut new_obj = serde_json::Map::new();
+                for (k, v) in obj {
+                    match self.get(&k) {
+                        Some(Selection::All) => {
+                            new_obj.insert(k, v);
+                        }
+                        Some(Selection::Mask(mask)) => {
+                            if let Some(v) = mask.apply_json(v) {
+                                new_obj.insert(k, v);
+                            }
+                        }
+                        None => {}
+                    }
+                }
+                Some(serde_json::Value::Object(new_obj))
+            }
+            serde_json::Value::Array(arr) => {
+                let sel = self.element_selection();
+                Some(serde_json::Value::Array(
+                    arr.into_iter()
+                        .map(|v| match &sel {
+                            None => self.apply_json(v),
+                            Some(Selection::All) => Some(v),
+                            Some(Selection::Mask(mask)) => mask.apply_json(v),
+                        })
+                        .map(Option::unwrap_or_default)
+                        .collect(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Applies the mask to a CBOR value. Only text keys of maps can be selected.
+    /// Returns `None` if nothing is selected from a scalar.
+    pub fn apply_cbor(&self, value: Value) -> Option<Value> {
+        if self.is_empty() {
+            return Some(value);
+        }
+
+        match value {
+            Value::Map(list) => Some(Value::Map(
+                list.into_iter()
+                    .filter_map(|(k, v)| {
+                        let sel = self.get(k.as_text()?)?;
+                        match sel {
+                            Selection::All => Some((k, v)),
+                            Selection::Mask(mask) => mask.apply_cbor(v).map(|v| (k, v)),
+                        }
+                    })
+                    .collect(),
+            )),
+            Value::Array(arr) => {
+                let sel = self.element_selection();
+                Some(Value::Array(
+                    arr.into_iter()
+                        .map(|v| match &sel {
+                            None => self.apply_cbor(v),
+                            Some(Selection::All) => Some(v),
+                            Some(Selection::Mask(mask)) => mask.apply_cbor(v),
+                        })
+                        .map(|v| v.unwrap_or(Value::Null))
+                        .collect(),
+                ))
+            }
+            Value::Tag(tag, v) => self.apply_cbor(*v).map(|v| Value::Tag(tag, Box::new(v))),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for JsonMask {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    // the depth of the current list
+    depth: usize,
+    // the number of keys parsed
+    keys: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    // list := [item] (',' [item])*
+    // Empty items are skipped, so "a,,b" and "a,b," are the same as "a,b".
+    fn list(&mut self) -> Result<JsonMask, String> {
+        let mut mask = JsonMask::default();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None | Some(')') => return Ok(mask),
+                Some(',') => {
+                    self.pos += 1;
+                }
+                Some(_) => {
+                    let (path, sel) = self.item()?;
+                    // nest the selection into the path from the leaf up
+                    let mut iter = path.into_iter().rev();
+                    let mut sel = (iter.next().unwrap(), sel);
+                    for key in iter {
+                        let mut m = JsonMask::default();
+                        m.insert(sel.0, sel.1);
+                        sel = (key, Selection::Mask(m));
+                    }
+                    mask.insert(sel.0, sel.1);
+
+                    self.skip_whitespace();
+                    match self.peek() {
+                        None | Some(',') | Some(')') => {}
+                        Some(c) => {
+                            return Err(format!(
+                                "invalid json mask: unexpected {:?} at position {}",
+                                c, self.pos
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // item := key ('/' key)* ['(' list ')']
+    fn item(&mut self) -> Result<(Vec<String>, Selection), String> {
+        let item_start = self.pos;
+        let mut path = vec![self.key()?];
+        while self.peek() == Some('/') {
+            self.pos += 1;
+            path.push(self.key()?);
+        }
+        if self.depth + path.len() > MAX_DEPTH {
+            return Err(format!(
+                "invalid json mask: deeper than {} at position {}",
+                MAX_DEPTH, item_start
+            ));
+        }
+
+        self.skip_whitespace();
+        if self.peek() != Some('(') {
+            return Ok((path, Selection::All));
+        }
+
+        let start = self.pos;
+        self.pos += 1;
+        self.depth += path.len();
+        let sub = self.list()?;
+        self.depth -= path.len();
+        if self.peek() != Some(')') {
+            return Err(format!(
+                "invalid json mask: unclosed '(' at position {}",
+                start
+            ));
+        }
+        self.pos += 1;
+        if sub.is_empty() {
+            return Err(format!(
+                "invalid json mask: empty sub-selection at position {}",
+                start
+            ));
+        }
+        Ok((path, Selection::Mask(sub)))
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        self.skip_whitespace();
+        let start = self.pos;
+        self.keys += 1;
+        if self.keys > MAX_KEYS {
+            return Err(format!(
+                "invalid json mask: more than {} keys at position {}",
+                MAX_KEYS, start
+            ));
+        }
+        let mut key = String::new();
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' => {
+                    self.pos += 1;
+                    let c = self.peek().ok_or_else(|| {
+                        format!(
+                            "invalid json mask: dangling escape at position {}",
+                            self.pos - 1
+                        )
+                    })?;
+                    key.push(c);
+                }
+                ',' | '/' | '(' | ')' => break,
+                c => key.push(c),
+            }
+            self.pos += 1;
+        }
+
+        let key = key.trim_end();
+        if key.is_empty() {
+            return Err(format!(
+                "invalid json mask: empty key at position {}",
+                start
+            ));
+        }
+        Ok(key.to_string())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(|c| c.is_whitespace()) {
+            self.pos += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ciborium::cbor;
+    use serde_json::json;
+
+    fn mask_json(mask: &str, value: serde_json::Value) -> Option<serde_json::Value> {
+        JsonMask::parse(mask).unwrap().apply_json(value)
+    }
+
+    #[test]
+    fn test_json_mask_parse() {
+        assert!(JsonMask::parse("").unwrap().is_empty());
+        assert!(JsonMask::parse(" , ").unwrap().is_empty());
+        assert_eq!(
+            JsonMask::parse("a/b,a/c").unwrap(),
+            JsonMask::parse("a(b,c)").unwrap()
+        );
+        assert_eq!(
+            JsonMask::parse("a, a/b").unwrap(),
+            JsonMask::parse("a").unwrap()
+        );
+        assert_eq!(
+            JsonMask::parse(" result ( number , hash ) ").unwrap(),
+            JsonMask::parse("result(number,hash)").unwrap()
+        );
+
+        for (mask, err) in [
+            ("a/", "empty key at position 2"),
+            ("/a", "empty key at position 0"),
+            ("a//b", "empty key at position 2"),
+            ("a(b", "unclosed '(' at position 1"),
+            ("a()", "empty sub-selection at position 1"),
+            ("a)", "unexpected ')' at position 1"),
+            ("a(b)c", "unexpected 'c' at position 4"),
+            ("a\\", "dangling escape at position 1"),
+        ] {
+            assert_eq!(
+                JsonMask::parse(mask).unwrap_err(),
+                format!("invalid json mask: {}", err),
+                "mask: {}",
+                mask
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_mask_hostile() {
+        let deep = "a(".repeat(100_000) + "b" + &")".repeat(100_000);
+        assert_eq!(
+            JsonMask::parse(&deep).unwrap_err(),
+            "invalid json mask: deeper than 32 at position 64"
+        );
+        let deep = vec!["a"; 100].join("/");
+        assert!(JsonMask::parse(&deep)
+            .unwrap_err()
+            .starts_with("invalid json mask: deeper than 32"));
+        let deep = "a/".repeat(20) + "b(" + &"c/".repeat(20) + "d)";
+        assert!(JsonMask::parse(&deep)
+            .unwrap_err()
+            .starts_with("invalid json mask: deeper than 32"));
+        assert!(JsonMask::parse(&("a/".repeat(MAX_DEPTH - 1) + "b")).is_ok());
+
+        let wide = vec!["a"; 100_000].join(",");
+        assert_eq!(
+            JsonMask::parse(&wide).unwrap_err(),
+            "invalid json mask: more than 256 keys at position 512"
+        );
+        assert!(JsonMask::parse(&vec!["a"; MAX_KEYS].join(",")).is_ok());
+    }
+
+    #[test]
+    fn test_json_mask_apply() {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "number": "0x1b4",
+                "hash": "0xdc0818cf",
+                "transactions": [
+                    {"hash": "0x01", "value": "0x10"},
+                    {"hash": "0x02", "value": "0x20"},
+                    "0x03",
+                ],
+                "a/b": true,
+            },
+        });
+
+        assert_eq!(mask_json("", body.clone()), Some(body.clone()));
+        assert_eq!(
+            mask_json("result/hash", body.clone()),
+            Some(json!({"result": {"hash": "0xdc0818cf"}}))
+        );
+        assert_eq!(
+            mask_json("id,result(number,hash)", body.clone()),
+            Some(json!({"id": 1, "result": {"number": "0x1b4", "hash": "0xdc0818cf"}}))
+        );
+        assert_eq!(
+            mask_json("result/transactions/hash", body.clone()),
+            Some(json!({"result": {"transactions": [{"hash": "0x01"}, {"hash": "0x02"}, null]}}))
+        );
+        assert_eq!(
+            mask_json("result/transactions/*/value", body.clone()),
+            Some(json!({"result": {"transactions": [{"value": "0x10"}, {"value": "0x20"}, null]}}))
+        );
+        assert_eq!(
+            mask_json("result/transactions/*", body.clone()),
+            Some(json!({"result": {"transactions": [
+                {"hash": "0x01", "value": "0x10"},
+                {"hash": "0x02", "value": "0x20"},
+                "0x03",
+            ]}}))
+        );
+        assert_eq!(
+            mask_json("*/number", body.clone()),
+            Some(json!({"result": {"number": "0x1b4"}}))
+        );
+        assert_eq!(
+            mask_json("result/a\\/b", body.clone()),
+            Some(json!({"result": {"a/b": true}}))
+        );
+        assert_eq!(mask_json("id/x", body.clone()), Some(json!({})));
+        assert_eq!(
+            mask_json("items/*/id", json!({"items": {"x": {"id": 1, "v": 2}}})),
+            Some(json!({"items": {"x": {"id": 1}}}))
+        );
+        assert_eq!(
+            mask_json(
+                "items/*/id",
+                json!({"items": [{"id": 1, "v": 2}, {"id": 3, "v": 4}, {"v": 5}]})
+            ),
+            Some(json!({"items": [{"id": 1}, {"id": 3}, {}]}))
+        );
+        assert_eq!(
+            mask_json(
+                "items(*/id,v)",
+                json!({"items": [{"id": 1, "v": 2, "x": 0}, {"id": 3, "v": 4}]})
+            ),
+            Some(json!({"items": [{"id": 1, "v": 2}, {"id": 3, "v": 4}]}))
+        );
+        assert_eq!(mask_json("a", json!(1)), None);
+    }
+
+    #[test]
+    fn test_json_mask_apply_cbor() {
+        let body = cbor!({
+            "id" => 1,
+            "result" => {
+                "number" => "0x1b4",
+                "hash" => "0xdc0818cf",
+                "transactions" => [{"hash" => "0x01", "value" => "0x10"}],
+            },
+            1 => "not a text key",
+        })
+        .unwrap();
+
+        let mask = JsonMask::parse("result/transactions/*/value").unwrap();
+        assert_eq!(
+            mask.apply_cbor(body.clone()),
+            Some(cbor!({"result" => {"transactions" => [{"value" => "0x10"}]}}).unwrap())
+        );
+
+        let mask = JsonMask::parse("result(hash,transactions/hash)").unwrap();
+        assert_eq!(
+            mask.apply_cbor(body.clone()),
+            Some(
+                cbor!({
+                    "result" => {
+                        "hash" => "0xdc0818cf",
+                        "transactions" => [{"hash" => "0x01"}],
+                    },
+                })
+                .unwrap()
+            )
+        );
+
+        let mask = JsonMask::parse("id").unwrap();
+        assert_eq!(
+            mask.mask_cbor(Value::Array(vec![body.clone(), body])),
+            cbor!([{"id" => 1}, {"id" => 1}]).unwrap()
+        );
+        assert_eq!(
+            mask.mask_cbor(Value::Tag(55799, Box::new(Value::Text("ok".to_string())))),
+            Value::Tag(55799, Box::new(Value::Text("ok".to_string())))
+        );
+    }
+
+    #[test]
+    fn test_json_mask_batch() {
+        let mask = JsonMask::parse("id,result/hash").unwrap();
+        let batch = json!([
+            {"jsonrpc": "2.0", "id": 1, "result": {"hash": "0x01", "number": "0x1"}},
+            {"jsonrpc": "2.0", "id": 2, "error": {"code": -32000, "message": "failed"}},
+        ]);
+        assert_eq!(
+            mask.mask_json(batch),
+            json!([{"id": 1, "result": {"hash": "0x01"}}, {"id": 2}])
+        );
+        assert_eq!(
+            mask.mask_json(json!([{"id": 1, "v": 2}, "0x01", 3, [{"id": 4}]])),
+            json!([{"id": 1}, null, null, [{"id": 4}]])
+        );
+        assert_eq!(
+            JsonMask::parse("*")
+                .unwrap()
+                .mask_json(json!(["0x01", 2, null])),
+            json!(["0x01", 2, null])
+        );
+        assert_eq!(
+            mask.mask_cbor(cbor!([{"id" => 1, "v" => 2}, "0x01"]).unwrap()),
+            cbor!([{"id" => 1}, null]).unwrap()
+        );
+        assert_eq!(mask.mask_json(json!("0x01")), json!("0x01"));
+        assert_eq!(mask.mask_json(json!(null)), json!(null));
+    }
+}
@@ -1,80 +1,377 @@
 use async_trait::async_trait;
-use idempotent_proxy_types::unix_ms;
+use ciborium::{from_reader, into_writer};
+use idempotent_proxy_types::{
+    clock::{Clock, SystemClock},
+    err_string,
+};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
 use std::{
-    collections::{
-        hash_map::{Entry, HashMap},
-        BTreeSet,
+    collections::{hash_map::HashMap, hash_map::RandomState, BTreeSet},
+    hash::BuildHasher,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError, TryLockError,
     },
-    sync::Arc,
-};
-use tokio::{
-    sync::RwLock,
-    time::{sleep, Duration},
 };
+use tokio::time::{sleep, Duration};
+
+use super::{CacheError, Cacher, LockToken};
+use crate::revocation::is_reserved_key;
 
-use super::Cacher;
+const DEFAULT_SHARDS: usize = 16;
 
 #[derive(Debug, Clone, Ord, PartialOrd, Eq, PartialEq)]
 struct PriorityKey(u64, String);
 
-type KV = HashMap<String, (u64, Vec<u8>)>;
+#[derive(Debug)]
+struct Item {
+    expire_at: u64,
+    // order in which the value was set, 0 for an in-flight lock
+    seq: u64,
+    // token of the in-flight lock
+    token: LockToken,
+    value: Vec<u8>,
+}
+
+// A completed entry in a snapshot file: key, expire_at (UNIX ms) and value.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotEntry(String, u64, ByteBuf);
+
+// Entry count and size (keys and values) of all shards.
+#[derive(Default)]
+struct Usage {
+    entries: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+impl Usage {
+    // Adds the entries and bytes if the totals stay within the limits.
+    fn try_add(&self, limits: (usize, usize), entries: usize, bytes: usize) -> bool {
+        let fits = |max: usize, n: usize| move |v: usize| v.checked_add(n).filter(|v| *v <= max);
+        if self
+            .entries
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, fits(limits.0, entries))
+            .is_err()
+        {
+            return false;
+        }
+        if self
+            .bytes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, fits(limits.1, bytes))
+            .is_err()
+        {
+            self.entries.fetch_sub(entries, Ordering::AcqRel);
+            return false;
+        }
+        true
+    }
+
+    fn sub(&self, entries: usize, bytes: usize) {
+        self.entries.fetch_sub(entries, Ordering::AcqRel);
+        self.bytes.fetch_sub(bytes, Ordering::AcqRel);
+    }
+}
+
+struct Shard {
+    kv: HashMap<String, Item>,
+    expiry: BTreeSet<PriorityKey>,    // by expiration
+    completed: BTreeSet<PriorityKey>, // by set order
+    usage: Arc<Usage>,
+}
+
+impl Shard {
+    fn new(usage: Arc<Usage>) -> Self {
+        Self {
+            kv: HashMap::new(),
+            expiry: BTreeSet::new(),
+            completed: BTreeSet::new(),
+            usage,
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Item> {
+        let item = self.kv.remove(key)?;
+        self.expiry
+            .remove(&PriorityKey(item.expire_at, key.to_string()));
+        self.release(key, &item);
+        Some(item)
+    }
+
+    fn release(&mut self, key: &str, item: &Item) {
+        if item.seq > 0 {
+            self.completed
+                .remove(&PriorityKey(item.seq, key.to_string()));
+        }
+        self.usage.sub(1, key.len() + item.value.len());
+    }
+
+    fn clean_expired(&mut self, now: u64) {
+        while let Some(PriorityKey(expire_at, key)) = self.expiry.pop_first() {
+            if expire_at > now {
+                self.expiry.insert(PriorityKey(expire_at, key));
+                break;
+            }
+
+            if let Some(item) = self.kv.remove(&key) {
+                self.release(&key, &item);
+            }
+        }
+    }
+
+    // Evicts the oldest completed entry except `keep` and the reserved keys.
+    // Returns false if there is none.
+    fn evict_one(&mut self, keep: &str) -> bool {
+        let key = match self
+            .completed
+            .iter()
+            .find(|pk| pk.1 != keep && !is_reserved_key(&pk.1))
+        {
+            Some(PriorityKey(_, key)) => key.clone(),
+            None => return false,
+        };
+        self.remove(&key);
+        true
+    }
+}
 
-#[derive(Clone, Default)]
+/// An in-memory cacher bounded by entry count and size (keys and values in bytes).
+///
+/// Keys are spread over shards, each with its own lock, map and expiry queue,
+/// and the limits apply to all shards together. When the cacher is full, the completed
+/// entries of the key's shard are evicted oldest-first, then those of the other shards;
+/// in-flight locks and revocations are never evicted, so `obtain` fails if they alone
+/// fill the cacher. Expired entries are removed by a single
+/// sweeper task, see `spawn_sweeper`.
+#[derive(Clone)]
 pub struct MemoryCacher {
-    priority_queue: Arc<RwLock<BTreeSet<PriorityKey>>>,
-    kv: Arc<RwLock<KV>>,
+    shards: Arc<Vec<Mutex<Shard>>>,
+    hasher: RandomState,
+    seq: Arc<AtomicU64>,
+    usage: Arc<Usage>,
+    // serializes snapshot writers, which share the temporary file
+    snapshot_lock: Arc<Mutex<()>>,
+    clock: Arc<dyn Clock>,
+    max_entries: usize,
+    max_bytes: usize,
+}
+
+impl Default for MemoryCacher {
+    fn default() -> Self {
+        Self::new(usize::MAX, usize::MAX)
+    }
 }
 
 impl MemoryCacher {
-    fn clean_expired_values(&self) -> tokio::task::JoinHandle<()> {
-        let kv = self.kv.clone();
-        let priority_queue = self.priority_queue.clone();
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        Self::with_shards(DEFAULT_SHARDS, max_entries, max_bytes)
+    }
+
+    pub fn with_shards(shards: usize, max_entries: usize, max_bytes: usize) -> Self {
+        let usage = Arc::new(Usage::default());
+        Self {
+            shards: Arc::new(
+                (0..shards.max(1))
+                    .map(|_| Mutex::new(Shard::new(usage.clone())))
+                    .collect(),
+            ),
+            hasher: RandomState::new(),
+            seq: Arc::new(AtomicU64::new(0)),
+            usage,
+            snapshot_lock: Arc::new(Mutex::new(())),
+            clock: Arc::new(SystemClock),
+            max_entries,
+            max_bytes,
+        }
+    }
+
+    /// Uses the clock for expirations instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Spawns a task that removes the expired entries at every `interval`.
+    pub fn spawn_sweeper(&self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let cacher = self.clone();
         tokio::spawn(async move {
-            let now = unix_ms();
-            let mut pq = priority_queue.write().await;
-            let mut kv = kv.write().await;
-            while let Some(PriorityKey(expire_at, key)) = pq.pop_first() {
-                if expire_at > now {
-                    pq.insert(PriorityKey(expire_at, key));
-                    break;
+            loop {
+                sleep(interval).await;
+                cacher.clean_expired_values();
+            }
+        })
+    }
+
+    fn clean_expired_values(&self) {
+        let now = self.clock.now_ms();
+        for shard in self.shards.iter() {
+            lock(shard).clean_expired(now);
+        }
+    }
+
+    /// Writes the completed, non-expired entries to a file, oldest first.
+    /// The file is replaced atomically, and concurrent snapshots are written one after
+    /// another. Returns the number of written entries.
+    pub fn snapshot(&self, path: &str) -> Result<usize, String> {
+        let _guard = self
+            .snapshot_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let now = self.clock.now_ms();
+        let mut entries: Vec<(u64, SnapshotEntry)> = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = lock(shard);
+            for PriorityKey(seq, key) in shard.completed.iter() {
+                if let Some(item) = shard.kv.get(key) {
+                    if item.expire_at > now {
+                        entries.push((
+                            *seq,
+                            SnapshotEntry(
+                                key.clone(),
+                                item.expire_at,
+                                ByteBuf::from(item.value.clone()),
+                            ),
+                        ));
+                    }
                 }
+            }
+        }
+        entries.sort_by_key(|(seq, _)| *seq);
+        let entries: Vec<SnapshotEntry> = entries.into_iter().map(|(_, e)| e).collect();
+
+        let mut data = Vec::new();
+        into_writer(&entries, &mut data).map_err(err_string)?;
+        let tmp = format!("{}.tmp", path);
+        std::fs::write(&tmp, data).map_err(err_string)?;
+        std::fs::rename(&tmp, path).map_err(err_string)?;
+        Ok(entries.len())
+    }
+
+    /// Loads the non-expired entries of a snapshot file, if it exists, as completed entries.
+    /// Existing keys are kept. Returns the number of loaded entries.
+    pub fn restore(&self, path: &str) -> Result<usize, String> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err.to_string()),
+        };
+        let entries: Vec<SnapshotEntry> = from_reader(&data[..]).map_err(err_string)?;
 
-                kv.remove(&key);
+        let now = self.clock.now_ms();
+        let mut loaded = 0;
+        for SnapshotEntry(key, expire_at, value) in entries {
+            if expire_at <= now || value.is_empty() {
+                continue;
             }
-        })
+            let mut shard = self.shard(&key);
+            if shard.kv.contains_key(&key)
+                || !self.reserve(&mut shard, &key, 1, key.len() + value.len())
+            {
+                continue;
+            }
+
+            let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+            shard.expiry.insert(PriorityKey(expire_at, key.clone()));
+            shard.completed.insert(PriorityKey(seq, key.clone()));
+            shard.kv.insert(
+                key,
+                Item {
+                    expire_at,
+                    seq,
+                    token: 0,
+                    value: value.into_vec(),
+                },
+            );
+            loaded += 1;
+        }
+        Ok(loaded)
     }
-}
 
-#[async_trait]
-impl Cacher for MemoryCacher {
-    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        let mut kv = self.kv.write().await;
-        let now = unix_ms();
-        match kv.entry(key.to_string()) {
-            Entry::Occupied(mut entry) => {
-                let (expire_at, value) = entry.get_mut();
-                if *expire_at > now {
-                    return Ok(false);
+    /// Spawns a task that writes a snapshot at every `interval`, to bound the loss on crashes.
+    pub fn spawn_snapshots(&self, path: String, interval: Duration) -> tokio::task::JoinHandle<()> {
+        let cacher = self.clone();
+        tokio::spawn(async move {
+            loop {
+                sleep(interval).await;
+                let c = cacher.clone();
+                let p = path.clone();
+                match tokio::task::spawn_blocking(move || c.snapshot(&p)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(err)) => {
+                        log::error!(target: "cacher", "memory snapshot failed: {}", err);
+                    }
+                    Err(err) => {
+                        log::error!(target: "cacher", "memory snapshot failed: {}", err);
+                    }
                 }
+            }
+        })
+    }
 
-                let mut pq = self.priority_queue.write().await;
-                pq.remove(&PriorityKey(*expire_at, key.to_string()));
+    fn shard(&self, key: &str) -> MutexGuard<'_, Shard> {
+        let i = self.hasher.hash_one(key) as usize % self.shards.len();
+        lock(&self.shards[i])
+    }
 
-                *expire_at = now + ttl;
-                *value = vec![];
-                pq.insert(PriorityKey(*expire_at, key.to_string()));
-                Ok(true)
+    // Reserves room for `entries` more entries and `bytes` more bytes, evicting the oldest
+    // completed entries of the locked shard except `keep`, then of the other shards.
+    // Returns false if they can not fit.
+    fn reserve(&self, shard: &mut Shard, keep: &str, entries: usize, bytes: usize) -> bool {
+        let limits = (self.max_entries, self.max_bytes);
+        while !self.usage.try_add(limits, entries, bytes) {
+            if !shard.evict_one(keep) && !self.evict_other() {
+                return false;
             }
-            Entry::Vacant(entry) => {
-                let expire_at = now + ttl;
-                entry.insert((expire_at, vec![]));
-                self.priority_queue
-                    .write()
-                    .await
-                    .insert(PriorityKey(expire_at, key.to_string()));
-                Ok(true)
+        }
+        true
+    }
+
+    // Evicts the oldest completed entry of another shard. Busy shards are skipped,
+    // including the one locked by the caller, so shards are never locked in a cycle.
+    fn evict_other(&self) -> bool {
+        self.shards.iter().any(|shard| match shard.try_lock() {
+            Ok(mut shard) => shard.evict_one(""),
+            Err(TryLockError::Poisoned(err)) => err.into_inner().evict_one(""),
+            Err(TryLockError::WouldBlock) => false,
+        })
+    }
+}
+
+// A panic while holding the lock does not leave a shard inconsistent.
+fn lock(shard: &Mutex<Shard>) -> MutexGuard<'_, Shard> {
+    shard.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[async_trait]
+impl Cacher for MemoryCacher {
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<Option<LockToken>, CacheError> {
+        let mut shard = self.shard(key);
+        let now = self.clock.now_ms();
+        if let Some(item) = shard.kv.get(key) {
+            if item.expire_at > now {
+                return Ok(None);
             }
+            shard.remove(key);
+        }
+
+        if !self.reserve(&mut shard, key, 1, key.len()) {
+            return Err(CacheError::Full);
         }
+
+        let expire_at = now + ttl;
+        // sequence numbers are unique, so are the tokens
+        let token = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        shard.kv.insert(
+            key.to_string(),
+            Item {
+                expire_at,
+                seq: 0,
+                token,
+                value: vec![],
+            },
+        );
+        shard.expiry.insert(PriorityKey(expire_at, key.to_string()));
+        Ok(Some(token))
     }
 
     async fn polling_get(
@@ -82,19 +379,13 @@ impl Cacher for MemoryCacher {
         key: &str,
         poll_interval: u64,
         mut counter: u64,
-    ) -> Result<Vec<u8>, String> {
+    ) -> Result<Vec<u8>, CacheError> {
         while counter > 0 {
-            let kv = self.kv.read().await;
-            let res = kv.get(key);
-            match res {
-                None => return Err("not obtained".to_string()),
-                Some((expire_at, value)) => {
-                    if *expire_at <= unix_ms() {
-                        self.clean_expired_values();
-                    }
-
-                    if !value.is_empty() {
-                        return Ok(value.clone());
+            match self.shard(key).kv.get(key) {
+                None => return Err(CacheError::NotObtained),
+                Some(item) => {
+                    if !item.value.is_empty() {
+                        return Ok(item.value.clone());
                     }
                 }
             }
@@ -103,39 +394,69 @@ impl Cacher for MemoryCacher {
             sleep(Duration::from_millis(poll_interval)).await;
         }
 
-        Err(("polling get cache timeout").to_string())
+        Err(CacheError::Timeout)
     }
 
-    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        let mut kv = self.kv.write().await;
-        match kv.get_mut(key) {
-            Some((expire_at, value)) => {
-                let now = unix_ms();
-                if *expire_at <= now {
-                    kv.remove(key);
-                    self.clean_expired_values();
-                    return Err("value expired".to_string());
-                }
-
-                let mut pq = self.priority_queue.write().await;
-                pq.remove(&PriorityKey(*expire_at, key.to_string()));
-
-                *expire_at = now + ttl;
-                *value = val;
-                pq.insert(PriorityKey(*expire_at, key.to_string()));
-                Ok(true)
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        match self.shard(key).kv.get(key) {
+            Some(item) if item.expire_at > self.clock.now_ms() && !item.value.is_empty() => {
+                Ok(Some(item.value.clone()))
             }
-            None => Err("not obtained".to_string()),
+            _ => Ok(None),
         }
     }
 
-    async fn del(&self, key: &str) -> Result<(), String> {
-        let mut kv = self.kv.write().await;
-        if let Some(val) = kv.remove(key) {
-            let mut pq = self.priority_queue.write().await;
-            pq.remove(&PriorityKey(val.0, key.to_string()));
+    async fn set(
+        &self,
+        key: &str,
+        token: LockToken,
+        val: Vec<u8>,
+        ttl: u64,
+    ) -> Result<bool, CacheError> {
+        let mut shard = self.shard(key);
+        let now = self.clock.now_ms();
+        let (expire_at, locked) = match shard.kv.get(key) {
+            Some(item) => (item.expire_at, item.seq == 0 && item.token == token),
+            None => return Err(CacheError::NotObtained),
+        };
+        if expire_at <= now {
+            shard.remove(key);
+            return Err(CacheError::Expired);
+        }
+        // taken over by another request, or already set
+        if !locked {
+            return Ok(false);
+        }
+
+        if key.len() + val.len() > self.max_bytes || !self.reserve(&mut shard, key, 0, val.len()) {
+            return Err(CacheError::Full);
         }
-        self.clean_expired_values();
+
+        shard
+            .expiry
+            .remove(&PriorityKey(expire_at, key.to_string()));
+        let expire_at = now + ttl;
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed) + 1;
+        shard.kv.insert(
+            key.to_string(),
+            Item {
+                expire_at,
+                seq,
+                token: 0,
+                value: val,
+            },
+        );
+        shard.expiry.insert(PriorityKey(expire_at, key.to_string()));
+        shard.completed.insert(PriorityKey(seq, key.to_string()));
+        Ok(true)
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        self.shard(key).remove(key);
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), CacheError> {
         Ok(())
     }
 }
@@ -143,17 +464,45 @@ impl Cacher for MemoryCacher {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::cache::conformance;
+    use idempotent_proxy_types::{clock::ManualClock, unix_ms};
+
+    #[derive(Debug, Default)]
+    struct Stats {
+        entries: usize,
+        expiry: usize,
+        completed: usize,
+        bytes: usize,
+    }
+
+    fn stats(mc: &MemoryCacher) -> Stats {
+        let mut rt = Stats::default();
+        for shard in mc.shards.iter() {
+            let shard = lock(shard);
+            rt.entries += shard.kv.len();
+            rt.expiry += shard.expiry.len();
+            rt.completed += shard.completed.len();
+            rt.bytes += shard
+                .kv
+                .iter()
+                .map(|(k, item)| k.len() + item.value.len())
+                .sum::<usize>();
+        }
+        assert_eq!(mc.usage.entries.load(Ordering::Acquire), rt.entries);
+        assert_eq!(mc.usage.bytes.load(Ordering::Acquire), rt.bytes);
+        rt
+    }
 
     #[tokio::test]
     async fn memory_cacher() {
         let mc = MemoryCacher::default();
 
-        assert!(mc.obtain("key1", 100).await.unwrap());
-        assert!(!mc.obtain("key1", 100).await.unwrap());
+        let token = mc.obtain("key1", 100).await.unwrap().unwrap();
+        assert_eq!(mc.obtain("key1", 100).await.unwrap(), None);
         assert!(mc.polling_get("key1", 10, 2).await.is_err());
-        assert!(mc.set("key", vec![1, 2, 3, 4], 100).await.is_err());
-        assert!(mc.set("key1", vec![1, 2, 3, 4], 100).await.is_ok());
-        assert!(!mc.obtain("key1", 100).await.unwrap());
+        assert!(mc.set("key", token, vec![1, 2, 3, 4], 100).await.is_err());
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_ok());
+        assert_eq!(mc.obtain("key1", 100).await.unwrap(), None);
         assert_eq!(
             mc.polling_get("key1", 10, 2).await.unwrap(),
             vec![1, 2, 3, 4]
@@ -163,12 +512,16 @@ mod test {
             vec![1, 2, 3, 4]
         );
 
+        assert_eq!(mc.get("key1").await.unwrap(), Some(vec![1, 2, 3, 4]));
+        assert_eq!(mc.get("key").await.unwrap(), None);
+
         assert!(mc.del("key").await.is_ok());
         assert!(mc.del("key1").await.is_ok());
+        assert_eq!(mc.get("key1").await.unwrap(), None);
         assert!(mc.polling_get("key1", 10, 2).await.is_err());
-        assert!(mc.set("key1", vec![1, 2, 3, 4], 100).await.is_err());
-        assert!(mc.obtain("key1", 100).await.unwrap());
-        assert!(mc.set("key1", vec![1, 2, 3, 4], 100).await.is_ok());
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_err());
+        let token = mc.obtain("key1", 100).await.unwrap().unwrap();
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_ok());
         assert_eq!(
             mc.polling_get("key1", 10, 2).await.unwrap(),
             vec![1, 2, 3, 4]
@@ -176,13 +529,13 @@ mod test {
 
         sleep(Duration::from_millis(200)).await;
         assert!(mc.polling_get("key1", 10, 2).await.is_ok());
-        assert!(mc.set("key1", vec![1, 2, 3, 4], 100).await.is_err());
+        assert!(mc.set("key1", token, vec![1, 2, 3, 4], 100).await.is_err());
         assert!(mc.del("key1").await.is_ok());
 
-        assert!(mc.obtain("key1", 100).await.unwrap());
+        assert!(mc.obtain("key1", 100).await.unwrap().is_some());
         sleep(Duration::from_millis(200)).await;
-        let _ = mc.clean_expired_values().await;
-        println!("{:?}", mc.priority_queue.read().await);
+        mc.clean_expired_values();
+        println!("{:?}", stats(&mc));
 
         let res = futures::try_join!(
             mc.obtain("key1", 100),
@@ -191,19 +544,139 @@ mod test {
         )
         .unwrap();
         match res {
-            (true, false, false) | (false, true, false) | (false, false, true) => {}
+            (Some(_), None, None) | (None, Some(_), None) | (None, None, Some(_)) => {}
             _ => panic!("unexpected result"),
         }
 
-        assert_eq!(mc.kv.read().await.len(), 1);
-        assert_eq!(mc.priority_queue.read().await.len(), 1);
+        assert_eq!(stats(&mc).entries, 1);
+        assert_eq!(stats(&mc).expiry, 1);
+
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats(&mc).entries, 1);
+        assert_eq!(stats(&mc).expiry, 1);
+        mc.clean_expired_values();
+
+        assert_eq!(stats(&mc).entries, 0);
+        assert_eq!(stats(&mc).expiry, 0);
+    }
+
+    #[tokio::test]
+    async fn memory_cacher_conformance() {
+        let clock = ManualClock::new(unix_ms());
+        let mc = MemoryCacher::default().with_clock(Arc::new(clock.clone()));
+        conformance::run(&mc, &conformance::TestTime::Manual(clock)).await;
+        mc.clean_expired_values();
+        assert_eq!(stats(&mc).entries, 0);
+        assert_eq!(stats(&mc).bytes, 0);
+    }
+
+    #[tokio::test]
+    async fn memory_cacher_limits() {
+        let mc = MemoryCacher::with_shards(1, 2, 100);
+        let token = mc.obtain("k1", 1000).await.unwrap().unwrap();
+        assert!(mc.set("k1", token, vec![1; 10], 1000).await.unwrap());
+        let token = mc.obtain("k2", 1000).await.unwrap().unwrap();
+        // k1 is the oldest completed entry
+        assert!(mc.obtain("k3", 1000).await.unwrap().is_some());
+        assert_eq!(mc.get("k1").await.unwrap(), None);
+        // in-flight locks are never evicted
+        assert_eq!(mc.obtain("k4", 1000).await, Err(CacheError::Full));
+        assert!(mc.set("k2", token, vec![2; 10], 1000).await.unwrap());
+        assert!(mc.obtain("k4", 1000).await.unwrap().is_some());
+        assert_eq!(mc.get("k2").await.unwrap(), None);
+        assert_eq!(stats(&mc).entries, 2);
+
+        let mc = MemoryCacher::with_shards(1, 100, 30);
+        let token = mc.obtain("k1", 1000).await.unwrap().unwrap();
+        assert!(mc.set("k1", token, vec![1; 10], 1000).await.unwrap());
+        let token = mc.obtain("k2", 1000).await.unwrap().unwrap();
+        assert!(mc.set("k2", token, vec![2; 10], 1000).await.unwrap());
+        assert_eq!(stats(&mc).bytes, 24);
+        let token = mc.obtain("k3", 1000).await.unwrap().unwrap();
+        assert!(mc.set("k3", token, vec![3; 29], 1000).await.is_err());
+        // k1 and k2 are evicted to fit k3
+        assert!(mc.set("k3", token, vec![3; 20], 1000).await.unwrap());
+        assert_eq!(mc.get("k1").await.unwrap(), None);
+        assert_eq!(mc.get("k2").await.unwrap(), None);
+        assert_eq!(mc.get("k3").await.unwrap(), Some(vec![3; 20]));
+        assert_eq!(stats(&mc).bytes, 22);
+        assert_eq!(stats(&mc).completed, 1);
+
+        // the limits apply to all shards together
+        let mc = MemoryCacher::with_shards(16, 4, usize::MAX);
+        let mut tokens = Vec::new();
+        for i in 0..4 {
+            tokens.push(mc.obtain(&format!("k{}", i), 1000).await.unwrap().unwrap());
+        }
+        assert_eq!(mc.obtain("k4", 1000).await, Err(CacheError::Full));
+        for (i, token) in tokens.into_iter().enumerate() {
+            let key = format!("k{}", i);
+            assert!(mc.set(&key, token, vec![1; 10], 1000).await.unwrap());
+        }
+        // completed entries are evicted from the key's shard first, then from the others
+        for i in 4..20 {
+            let key = format!("k{}", i);
+            let token = mc.obtain(&key, 1000).await.unwrap().unwrap();
+            assert!(mc.set(&key, token, vec![1; 10], 1000).await.unwrap());
+        }
+        assert_eq!(stats(&mc).entries, 4);
+        assert_eq!(stats(&mc).completed, 4);
+        assert!(mc.get("k19").await.unwrap().is_some());
+
+        let mc = MemoryCacher::new(100, 1000);
+        let sweeper = mc.spawn_sweeper(Duration::from_millis(50));
+        let token = mc.obtain("k1", 10).await.unwrap().unwrap();
+        assert!(mc.set("k1", token, vec![1; 10], 100).await.unwrap());
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(stats(&mc).entries, 0);
+        assert_eq!(stats(&mc).expiry, 0);
+        assert_eq!(stats(&mc).completed, 0);
+        assert_eq!(stats(&mc).bytes, 0);
+        sweeper.abort();
+    }
+
+    #[tokio::test]
+    async fn memory_cacher_snapshot() {
+        let path = std::env::temp_dir().join(format!("idempotent-proxy-snapshot-{}", unix_ms()));
+        let path = path.to_str().unwrap();
 
+        let mc = MemoryCacher::default();
+        assert_eq!(mc.restore(path).unwrap(), 0);
+        let token = mc.obtain("k1", 10000).await.unwrap().unwrap();
+        assert!(mc.set("k1", token, vec![1, 2, 3], 10000).await.unwrap());
+        let token = mc.obtain("k2", 10000).await.unwrap().unwrap();
+        assert!(mc.set("k2", token, vec![4, 5], 10000).await.unwrap());
+        let token = mc.obtain("k3", 100).await.unwrap().unwrap();
+        assert!(mc.set("k3", token, vec![6], 100).await.unwrap());
+        // in-flight locks are not written
+        assert!(mc.obtain("k4", 10000).await.unwrap().is_some());
         sleep(Duration::from_millis(200)).await;
-        assert_eq!(mc.kv.read().await.len(), 1);
-        assert_eq!(mc.priority_queue.read().await.len(), 1);
-        let _ = mc.clean_expired_values().await;
+        assert_eq!(mc.snapshot(path).unwrap(), 2);
+        // concurrent snapshots, such as a periodic and the final one, share the temporary file
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let mc = mc.clone();
+                let path = path.to_string();
+                std::thread::spawn(move || mc.snapshot(&path))
+            })
+            .collect();
+        for writer in writers {
+            assert_eq!(writer.join().unwrap().unwrap(), 2);
+        }
 
-        assert!(mc.kv.read().await.is_empty());
-        assert!(mc.priority_queue.read().await.is_empty());
+        let mc = MemoryCacher::with_shards(1, 1, usize::MAX);
+        // k1 is evicted by the newer k2
+        assert_eq!(mc.restore(path).unwrap(), 2);
+        assert_eq!(mc.get("k1").await.unwrap(), None);
+        assert_eq!(mc.get("k2").await.unwrap(), Some(vec![4, 5]));
+        assert_eq!(mc.obtain("k2", 10000).await.unwrap(), None);
+        assert_eq!(stats(&mc).bytes, 4);
+
+        let mc = MemoryCacher::default();
+        assert_eq!(mc.restore(path).unwrap(), 2);
+        assert_eq!(mc.get("k1").await.unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(mc.get("k3").await.unwrap(), None);
+        assert_eq!(mc.get("k4").await.unwrap(), None);
+        std::fs::remove_file(path).unwrap();
     }
 }
@@ -8,57 +8,158 @@ use http::{
     header::{HeaderMap, HeaderName, HeaderValue},
     StatusCode,
 };
-use idempotent_proxy_types::err_string;
+use idempotent_proxy_types::{
+    auth::SigningKey, clock::Clock, err_string, response::ResponseSignature,
+    HEADER_X_PROXY_SIGNATURE,
+};
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 
+mod canonical;
+mod codec;
+#[cfg(test)]
+mod conformance;
+mod embedded;
+mod l1;
+mod mask;
 mod memory;
 mod redis;
-
+mod scrub;
+mod transform;
+
+pub use canonical::*;
+pub use codec::*;
+pub use embedded::*;
+pub use l1::*;
+pub use mask::*;
 pub use memory::*;
 pub use redis::*;
+pub use scrub::*;
+pub use transform::*;
 
+/// Caches responses in a `Cacher` backend, optionally with an in-process L1 cache.
 pub struct HybridCacher {
     pub poll_interval: u64,
     pub cache_ttl: u64,
-    cache: CacherEntry,
+    cache: Box<dyn Cacher>,
+    l1: Option<L1Cache>,
 }
 
 impl HybridCacher {
-    pub fn new(poll_interval: u64, cache_ttl: u64, cache: CacherEntry) -> Self {
+    pub fn new(poll_interval: u64, cache_ttl: u64, cache: Box<dyn Cacher>) -> Self {
         Self {
             poll_interval,
             cache_ttl,
             cache,
+            l1: None,
         }
     }
+
+    /// Keeps completed values in an in-process L1 cache in front of the backend,
+    /// so duplicate requests skip the backend round-trips. Locks stay in the backend.
+    pub fn with_l1(mut self, l1: L1Cache) -> Self {
+        self.l1 = Some(l1);
+        self
+    }
+
+    /// Returns the completed value from the L1 cache only.
+    pub fn l1_get(&self, key: &str) -> Option<Vec<u8>> {
+        self.l1.as_ref().and_then(|l1| l1.get(key))
+    }
+
+    fn l1_put(&self, key: &str, val: &[u8], ttl: u64) {
+        if let Some(l1) = &self.l1 {
+            l1.put(key, val.to_vec(), ttl);
+        }
+    }
+}
+
+/// Errors of a `Cacher` backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CacheError {
+    /// The key was not obtained, or it expired and was removed.
+    NotObtained,
+    /// The lock of the key expired before the value was set.
+    Expired,
+    /// The value was not set within the polling time.
+    Timeout,
+    /// The backend has no room for the entry.
+    Full,
+    /// Error from the backend store, such as a connection error.
+    Backend(String),
+}
+
+impl CacheError {
+    pub fn backend(err: impl std::fmt::Display) -> Self {
+        Self::Backend(err.to_string())
+    }
 }
 
-pub enum CacherEntry {
-    Memory(MemoryCacher),
-    Redis(RedisClient),
+impl std::fmt::Display for CacheError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotObtained => f.write_str("not obtained"),
+            Self::Expired => f.write_str("value expired"),
+            Self::Timeout => f.write_str("polling get cache timeout"),
+            Self::Full => f.write_str("cacher is full"),
+            Self::Backend(err) => write!(f, "cacher backend error: {}", err),
+        }
+    }
 }
 
+impl std::error::Error for CacheError {}
+
+/// The fencing token of a lock taken by `Cacher::obtain`.
+pub type LockToken = u64;
+
+/// A backend store of idempotent responses, shared by all proxy instances that should
+/// deduplicate requests between them. A key goes through these states:
+///
+/// 1. missing or expired: `obtain` takes the lock and returns its token,
+///    the caller should then make the request and `set` the response with the token.
+/// 2. obtained: `obtain` returns None, `polling_get` waits for the value,
+///    `get` returns None. `set` with the token of the lock stores the value.
+/// 3. completed: `obtain` returns None, `polling_get` and `get` return the value.
+///
+/// `set` is fenced: once a lock expired and was obtained again, the previous holder
+/// can not overwrite the value of the new one.
+/// `del` removes the key in any state, such as when the request failed.
+/// Values must not be empty nor start with a zero byte, which backends may use to
+/// mark locks. All TTLs are in milliseconds.
 #[async_trait]
-pub trait Cacher {
-    async fn obtain(&self, key: &str, ttl_ms: u64) -> Result<bool, String>;
+pub trait Cacher: Send + Sync {
+    /// Takes the lock of the key if it is missing or expired, atomically.
+    /// Returns the token of the lock, or None if the key is locked or completed.
+    async fn obtain(&self, key: &str, ttl_ms: u64) -> Result<Option<LockToken>, CacheError>;
+    /// Waits for the value of an obtained key, polling up to `counter` times.
+    /// Fails with `NotObtained` if the key is missing and `Timeout` if the value is not set.
     async fn polling_get(
         &self,
         key: &str,
         poll_interval_ms: u64,
         counter: u64,
-    ) -> Result<Vec<u8>, String>;
-    async fn set(&self, key: &str, val: Vec<u8>, ttl_ms: u64) -> Result<bool, String>;
-    async fn del(&self, key: &str) -> Result<(), String>;
+    ) -> Result<Vec<u8>, CacheError>;
+    /// Returns the value if it has been set, None if the key is missing or not set yet.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError>;
+    /// Sets the value of a key locked with the token and renews its TTL.
+    /// Returns false, or fails with `NotObtained` or `Expired`, if the value is not stored,
+    /// such as when the lock was taken over by another request or the value is already set.
+    async fn set(
+        &self,
+        key: &str,
+        token: LockToken,
+        val: Vec<u8>,
+        ttl_ms: u64,
+    ) -> Result<bool, CacheError>;
+    async fn del(&self, key: &str) -> Result<(), CacheError>;
+    /// Checks that the backend is reachable.
+    async fn ping(&self) -> Result<(), CacheError>;
 }
 
 #[async_trait]
 impl Cacher for HybridCacher {
-    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        match &self.cache {
-            CacherEntry::Memory(cacher) => cacher.obtain(key, ttl).await,
-            CacherEntry::Redis(cacher) => cacher.obtain(key, ttl).await,
-        }
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<Option<LockToken>, CacheError> {
+        self.cache.obtain(key, ttl).await
     }
 
     async fn polling_get(
@@ -66,34 +167,105 @@ impl Cacher for HybridCacher {
         key: &str,
         poll_interval: u64,
         counter: u64,
-    ) -> Result<Vec<u8>, String> {
-        match &self.cache {
-            CacherEntry::Memory(cacher) => cacher.polling_get(key, poll_interval, counter).await,
-            CacherEntry::Redis(cacher) => cacher.polling_get(key, poll_interval, counter).await,
+    ) -> Result<Vec<u8>, CacheError> {
+        if let Some(val) = self.l1_get(key) {
+            return Ok(val);
         }
+        let val = self.cache.polling_get(key, poll_interval, counter).await?;
+        self.l1_put(key, &val, self.cache_ttl);
+        Ok(val)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        if let Some(val) = self.l1_get(key) {
+            return Ok(Some(val));
+        }
+        let val = self.cache.get(key).await?;
+        if let Some(val) = &val {
+            self.l1_put(key, val, self.cache_ttl);
+        }
+        Ok(val)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        token: LockToken,
+        val: Vec<u8>,
+        ttl: u64,
+    ) -> Result<bool, CacheError> {
+        let l1_val = self.l1.as_ref().map(|_| val.clone());
+        let res = self.cache.set(key, token, val, ttl).await?;
+        if let (true, Some(l1), Some(val)) = (res, &self.l1, l1_val) {
+            l1.put(key, val, ttl);
+        }
+        Ok(res)
+    }
+
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        if let Some(l1) = &self.l1 {
+            l1.remove(key);
+        }
+        self.cache.del(key).await
+    }
+
+    async fn ping(&self) -> Result<(), CacheError> {
+        self.cache.ping().await
     }
+}
+
+/// Format of a JSON or CBOR response body.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BodyFormat {
+    Json,
+    Cbor,
+}
 
-    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        match &self.cache {
-            CacherEntry::Memory(cacher) => cacher.set(key, val, ttl).await,
-            CacherEntry::Redis(cacher) => cacher.set(key, val, ttl).await,
+impl BodyFormat {
+    pub fn from_mime(mime: &str) -> Option<Self> {
+        if mime.contains("application/json") {
+            Some(Self::Json)
+        } else if mime.contains("application/cbor") {
+            Some(Self::Cbor)
+        } else {
+            None
         }
     }
 
-    async fn del(&self, key: &str) -> Result<(), String> {
-        match &self.cache {
-            CacherEntry::Memory(cacher) => cacher.del(key).await,
-            CacherEntry::Redis(cacher) => cacher.del(key).await,
+    pub fn mime(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Cbor => "application/cbor",
         }
     }
 }
 
+/// Processing of JSON and CBOR response bodies before caching,
+/// in order of mask, transform, format conversion and canonicalization.
+#[derive(Debug, Clone, Default)]
+pub struct BodyOptions {
+    pub mask: JsonMask,
+    pub transform: Option<JsonTransform>,
+    pub canonical: Option<Canonicalizer>,
+    /// Converts the body to this format if it differs from the upstream one.
+    pub format: Option<BodyFormat>,
+}
+
+/// Key to sign responses, see `ResponseSignature`.
+pub struct ResponseSigner {
+    pub key: SigningKey,
+    pub kid: Option<String>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct ResponseData {
     pub status: u16,
     pub headers: Vec<(String, String)>,
     pub body: ByteBuf,
     pub mime: String,
+    /// The x-proxy-signature header, computed once before caching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signature: Option<String>,
 }
 
 impl Default for ResponseData {
@@ -109,6 +281,7 @@ impl ResponseData {
             headers: Vec::new(),
             body: ByteBuf::new(),
             mime: "text/plain".to_string(),
+            signature: None,
         }
     }
 
@@ -139,47 +312,146 @@ impl ResponseData {
         }
     }
 
-    pub fn with_body(&mut self, body: &[u8], filtering: &str) -> Result<(), String> {
-        let filtering: Vec<&str> = split_filtering(filtering);
-        if self.status >= 300 || filtering.is_empty() {
-            self.body.extend_from_slice(body);
-            return Ok(());
-        }
-
-        match &self.mime {
-            v if !filtering.is_empty() && v.contains("application/json") => {
-                let obj: serde_json::Value = serde_json::from_slice(body).map_err(err_string)?;
-                let mut new_obj = serde_json::Map::with_capacity(filtering.len());
-                for k in filtering {
-                    if let Some(v) = obj.get(k) {
-                        new_obj.insert(k.to_string(), v.to_owned());
+    pub fn with_body(&mut self, body: &[u8], opts: &BodyOptions) -> Result<(), String> {
+        let format = BodyFormat::from_mime(&self.mime);
+        let target = opts.format.or(format);
+        let format = match format {
+            Some(format)
+                if self.status < 300
+                    && (!opts.mask.is_empty()
+                        || opts.transform.is_some()
+                        || opts.canonical.is_some()
+                        || target != Some(format)) =>
+            {
+                format
+            }
+            _ => {
+                self.body.extend_from_slice(body);
+                return Ok(());
+            }
+        };
+
+        let buf = match format {
+            BodyFormat::Json => {
+                let mut obj: serde_json::Value =
+                    serde_json::from_slice(body).map_err(err_string)?;
+                if !opts.mask.is_empty() {
+                    obj = opts.mask.mask_json(obj);
+                }
+                if let Some(tf) = &opts.transform {
+                    obj = tf.apply_json(&obj)?;
+                }
+                match target {
+                    Some(BodyFormat::Cbor) => {
+                        encode_cbor(Value::serialized(&obj).map_err(err_string)?, opts)?
                     }
+                    _ => encode_json(obj, opts)?,
                 }
-                self.body = ByteBuf::from(serde_json::to_vec(&new_obj).map_err(err_string)?);
             }
-            v if !filtering.is_empty() && v.contains("application/cbor") => {
-                let obj: Value = from_reader(body).map_err(err_string)?;
-                let obj = obj
-                    .into_map()
-                    .map(|mut list| {
-                        list.retain(|v| v.0.as_text().is_some_and(|t| filtering.contains(&t)));
-                        list
-                    })
-                    .map(Value::Map);
-                let mut buf = Vec::new();
-                match obj {
-                    Ok(v) => into_writer(&v, &mut buf).map_err(err_string)?,
-                    Err(_) => into_writer(&Value::Map(vec![]), &mut buf).map_err(err_string)?,
+            BodyFormat::Cbor => {
+                let mut obj: Value = from_reader(body).map_err(err_string)?;
+                if !opts.mask.is_empty() {
+                    obj = opts.mask.mask_cbor(obj);
+                }
+                if let Some(tf) = &opts.transform {
+                    obj = tf.apply_cbor(&obj)?;
+                }
+                match target {
+                    Some(BodyFormat::Json) => {
+                        encode_json(serde_json::to_value(&obj).map_err(err_string)?, opts)?
+                    }
+                    _ => encode_cbor(obj, opts)?,
                 }
-                self.body = ByteBuf::from(buf);
             }
-            _ => {
-                self.body.extend_from_slice(body);
+        };
+
+        self.body = ByteBuf::from(buf);
+        if let Some(target) = target.filter(|t| *t != format) {
+            self.mime = target.mime().to_string();
+        }
+        Ok(())
+    }
+
+    /// Returns the size of the response headers and body on the wire, including the
+    /// `content-type`, `content-length` and `x-proxy-signature` headers of `into_response`
+    /// and the `date` header added by the HTTP server.
+    pub fn size(&self) -> usize {
+        fn header_size(name: &str, value_len: usize) -> usize {
+            name.len() + value_len + 4 // ": " and CRLF
+        }
+
+        let mut size: usize = self
+            .headers
+            .iter()
+            .map(|(k, v)| header_size(k, v.len()))
+            .sum();
+        size += header_size("content-type", self.mime.len());
+        size += header_size("content-length", self.body.len().to_string().len());
+        if let Some(sig) = &self.signature {
+            size += header_size(HEADER_X_PROXY_SIGNATURE.as_str(), sig.len());
+        }
+        if !self.headers.iter().any(|(k, _)| k == "date") {
+            // "Wed, 22 May 2024 11:11:17 GMT"
+            size += header_size("date", 29);
+        }
+        size + self.body.len()
+    }
+
+    /// Replaces the response with a 413 error if it is larger than `max_response_bytes`.
+    /// The error only depends on the limit, so every ICP replica gets the same response.
+    /// It should run after `sign`, as the signature counts. The 413 error is signed with
+    /// `sign`, and the signature is dropped if the signed error exceeds the limit.
+    /// Returns true if the response is replaced.
+    pub fn limit_size(&mut self, max_response_bytes: usize, sign: impl FnOnce(&mut Self)) -> bool {
+        if self.size() <= max_response_bytes {
+            return false;
+        }
+        let mut rd = Self::new(413);
+        rd.mime = "application/json".to_string();
+        rd.body = ByteBuf::from(format!(
+            r#"{{"error":"response too large","max_response_bytes":{}}}"#,
+            max_response_bytes
+        ));
+        sign(&mut rd);
+        if rd.size() > max_response_bytes {
+            rd.signature = None;
+        }
+        *self = rd;
+        true
+    }
+
+    /// Redacts configured secrets from the headers and the body.
+    /// It should run after `with_body` and before `sign`.
+    pub fn scrub(&mut self, scrubber: &SecretScrubber) -> Result<(), String> {
+        for (_, v) in self.headers.iter_mut() {
+            if let Some(s) = scrubber.scrub_str(v) {
+                *v = s;
             }
         }
+        if let Some(body) = scrubber.scrub_body(&self.mime, &self.body)? {
+            self.body = ByteBuf::from(body);
+        }
         Ok(())
     }
 
+    /// Signs the status, the content-type and other headers, the body
+    /// and the idempotency key of the request, at the clock's time.
+    pub fn sign(&mut self, signer: &ResponseSigner, idempotency_key: &str, clock: &dyn Clock) {
+        let mut headers = Vec::with_capacity(self.headers.len() + 1);
+        headers.push(("content-type".to_string(), self.mime.clone()));
+        headers.extend_from_slice(&self.headers);
+        let sig = ResponseSignature::sign(
+            &signer.key,
+            signer.kid.clone(),
+            clock.now_ms(),
+            idempotency_key,
+            self.status,
+            &headers,
+            &self.body,
+        );
+        self.signature = Some(sig.to_header());
+    }
+
     pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
         let mut buf = Vec::new();
         into_writer(self, &mut buf).map_err(err_string)?;
@@ -195,6 +467,24 @@ impl TryFrom<&[u8]> for ResponseData {
     }
 }
 
+fn encode_json(obj: serde_json::Value, opts: &BodyOptions) -> Result<Vec<u8>, String> {
+    match &opts.canonical {
+        Some(c) => c.canonical_json(obj),
+        None => serde_json::to_vec(&obj).map_err(err_string),
+    }
+}
+
+fn encode_cbor(obj: Value, opts: &BodyOptions) -> Result<Vec<u8>, String> {
+    match &opts.canonical {
+        Some(c) => c.canonical_cbor(obj),
+        None => {
+            let mut buf = Vec::new();
+            into_writer(&obj, &mut buf).map_err(err_string)?;
+            Ok(buf)
+        }
+    }
+}
+
 fn split_filtering(filtering: &str) -> Vec<&str> {
     filtering
         .split(',')
@@ -228,6 +518,12 @@ impl IntoResponse for ResponseData {
         );
         res.headers_mut()
             .insert(http::header::CONTENT_LENGTH, len.into());
+        if let Some(sig) = self.signature {
+            res.headers_mut().insert(
+                &HEADER_X_PROXY_SIGNATURE,
+                HeaderValue::from_bytes(sig.as_bytes()).unwrap(),
+            );
+        }
         res
     }
 }
@@ -236,6 +532,30 @@ impl IntoResponse for ResponseData {
 mod test {
     use super::*;
     use hex::prelude::*;
+    use idempotent_proxy_types::clock::ManualClock;
+
+    #[tokio::test]
+    async fn test_hybrid_cacher_l1() {
+        let hc = HybridCacher::new(10, 1000, Box::new(MemoryCacher::default()))
+            .with_l1(L1Cache::new(10, 1000));
+        let token = hc.obtain("k1", 1000).await.unwrap().unwrap();
+        assert_eq!(hc.l1_get("k1"), None);
+        assert_eq!(
+            hc.set("k2", token, vec![1], 1000).await,
+            Err(CacheError::NotObtained)
+        );
+        assert_eq!(hc.polling_get("k1", 10, 2).await, Err(CacheError::Timeout));
+        assert!(hc.set("k1", token, vec![1, 2, 3], 1000).await.unwrap());
+        assert_eq!(hc.l1_get("k1"), Some(vec![1, 2, 3]));
+        assert_eq!(hc.polling_get("k1", 10, 2).await.unwrap(), vec![1, 2, 3]);
+
+        // locks stay in the backend
+        assert_eq!(hc.obtain("k1", 1000).await.unwrap(), None);
+        hc.del("k1").await.unwrap();
+        assert_eq!(hc.l1_get("k1"), None);
+        assert_eq!(hc.get("k1").await.unwrap(), None);
+        assert!(hc.obtain("k1", 1000).await.unwrap().is_some());
+    }
 
     #[test]
     fn test_split_filtering() {
@@ -255,6 +575,38 @@ mod test {
         assert_eq!(rd2, rd);
         println!("rd: {}", data.to_lower_hex_string());
 
+        let signer = ResponseSigner {
+            key: SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[1u8; 32])),
+            kid: None,
+        };
+        let mut rd2 = rd.clone();
+        rd2.sign(&signer, "key001", &ManualClock::new(1717000000000));
+        let data = rd2.to_bytes().unwrap();
+        assert_eq!(ResponseData::try_from(data.as_slice()).unwrap(), rd2);
+        let res = rd2.into_response();
+        let headers: Vec<(String, String)> = res
+            .headers()
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_str().unwrap().to_string()))
+            .collect();
+        let sig = ResponseSignature::from_header(
+            res.headers()
+                .get(&HEADER_X_PROXY_SIGNATURE)
+                .unwrap()
+                .to_str()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(sig.timestamp, 1717000000000);
+        sig.verify(
+            &signer.key.public_key(),
+            "key001",
+            200,
+            &headers,
+            b"Hello, World!",
+        )
+        .unwrap();
+
         let mut rd = ResponseData::new(200);
         let mut headers = HeaderMap::new();
         headers.insert("Date", "Wed, 22 May 2024 11:11:17 GMT".parse().unwrap());
@@ -268,11 +620,94 @@ mod test {
                 "date".to_string(),
                 "Wed, 22 May 2024 11:11:17 GMT".to_string()
             )]
-        )
+        );
+
+        let size = rd.size();
+        assert_eq!(size, 88);
+        let rd2 = rd.clone();
+        assert!(!rd.limit_size(size, |_| {}));
+        assert_eq!(rd, rd2);
+        assert!(rd.limit_size(size - 1, |_| {}));
+        assert_eq!(rd.status, 413);
+        assert_eq!(rd.mime, "application/json");
+        assert_eq!(
+            rd.body.as_slice(),
+            br#"{"error":"response too large","max_response_bytes":87}"#
+        );
+        assert!(rd.headers.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_response_data_size_signed() {
+        let signer = ResponseSigner {
+            key: SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[1u8; 32])),
+            kid: Some("k1".to_string()),
+        };
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json".to_string();
+        rd.headers
+            .push(("x-request-id".to_string(), "abc".to_string()));
+        rd.body.extend_from_slice(br#"{"result":"0x0000"}"#);
+        rd.sign(&signer, "key001", &ManualClock::new(1717000000000));
+
+        // the size counts every header on the wire
+        let size = rd.size();
+        let app = axum::Router::new().route(
+            "/",
+            axum::routing::get({
+                let rd = rd.clone();
+                move || async move { rd }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let res = reqwest::get(format!("http://{}/", addr)).await.unwrap();
+        let wire: usize = res
+            .headers()
+            .iter()
+            .map(|(k, v)| k.as_str().len() + v.len() + 4)
+            .sum::<usize>()
+            + res.bytes().await.unwrap().len();
+        assert_eq!(size, wire);
+
+        // a signed response exactly at the limit is kept with its signature
+        let sign =
+            |rd: &mut ResponseData| rd.sign(&signer, "key001", &ManualClock::new(1717000000000));
+        let signed = rd.clone();
+        assert!(!rd.limit_size(size, sign));
+        assert_eq!(rd, signed);
+        assert!(rd.signature.is_some());
+        assert!(rd.limit_size(size - 1, sign));
+        assert_eq!(rd.status, 413);
+        assert!(rd.size() < size);
+
+        // a signed 413 error within the limit keeps its signature
+        let mut rd = signed.clone();
+        rd.body.extend_from_slice(&[b' '; 1000]);
+        assert!(rd.limit_size(1000, sign));
+        assert_eq!(rd.status, 413);
+        assert!(rd.signature.is_some());
+        assert!(rd.size() <= 1000);
+
+        // a limit just above the bare 413 error drops the signature of the error
+        let mut bare = signed.clone();
+        assert!(bare.limit_size(100, |_| {}));
+        let limit = bare.size() + 1;
+        assert!(limit > 100 && limit < 1000);
+        let mut rd = signed.clone();
+        assert!(rd.limit_size(limit, sign));
+        assert_eq!(rd.status, 413);
+        assert!(rd.signature.is_none());
+        assert!(rd.size() <= limit);
+        let mut expected = signed.clone();
+        expected.limit_size(limit, |_| {});
+        assert_eq!(rd, expected);
     }
 
     #[test]
     fn test_response_data_in_json() {
+        use ciborium::cbor;
         use serde_json::json;
 
         let mut rd = ResponseData::new(200);
@@ -291,7 +726,10 @@ mod test {
 
         rd.with_body(
             serde_json::to_vec(&body).unwrap().as_slice(),
-            "args,url,Origin",
+            &BodyOptions {
+                mask: "args,url,Origin".parse().unwrap(),
+                ..Default::default()
+            },
         )
         .unwrap();
         assert_eq!(
@@ -299,6 +737,57 @@ mod test {
             r#"{"args":{"api-key":"abc123"},"url":"https://httpbin.org/get?api-key=abc123"}"#
                 .as_bytes()
         );
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json".to_string();
+        rd.with_body(
+            serde_json::to_vec(&body).unwrap().as_slice(),
+            &BodyOptions {
+                mask: "args,url".parse().unwrap(),
+                transform: Some(JsonTransform::compile("[args.\"api-key\", origin]").unwrap()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(rd.body.as_slice(), r#"["abc123",null]"#.as_bytes());
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/json; charset=utf-8".to_string();
+        rd.with_body(
+            serde_json::to_vec(&body).unwrap().as_slice(),
+            &BodyOptions {
+                mask: "args,url".parse().unwrap(),
+                format: Some(BodyFormat::Cbor),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(rd.mime, "application/cbor");
+        let obj: Value = from_reader(rd.body.as_slice()).unwrap();
+        assert_eq!(
+            obj,
+            cbor!({
+                "args" => {"api-key" => "abc123"},
+                "url" => "https://httpbin.org/get?api-key=abc123",
+            })
+            .unwrap()
+        );
+
+        // no conversion for the same format or other mime types
+        for mime in ["application/json", "text/plain"] {
+            let mut rd = ResponseData::new(200);
+            rd.mime = mime.to_string();
+            rd.with_body(
+                b"{ \"a\": 1 }",
+                &BodyOptions {
+                    format: Some(BodyFormat::Json),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(rd.mime, mime);
+            assert_eq!(rd.body.as_slice(), b"{ \"a\": 1 }");
+        }
     }
 
     #[test]
@@ -320,7 +809,14 @@ mod test {
         .unwrap();
         let mut buf = Vec::new();
         into_writer(&body, &mut buf).unwrap();
-        rd.with_body(&buf, "args,url,Origin").unwrap();
+        rd.with_body(
+            &buf,
+            &BodyOptions {
+                mask: "args,url,Origin".parse().unwrap(),
+                ..Default::default()
+            },
+        )
+        .unwrap();
 
         let body = cbor!({
             "args" => {"api-key" => "abc123"},
@@ -330,5 +826,22 @@ mod test {
         buf.clear();
         into_writer(&body, &mut buf).unwrap();
         assert_eq!(rd.body.as_slice(), buf.as_slice());
+
+        let mut rd = ResponseData::new(200);
+        rd.mime = "application/cbor".to_string();
+        rd.with_body(
+            &buf,
+            &BodyOptions {
+                format: Some(BodyFormat::Json),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(rd.mime, "application/json");
+        assert_eq!(
+            rd.body.as_slice(),
+            r#"{"args":{"api-key":"abc123"},"url":"https://httpbin.org/get?api-key=abc123"}"#
+                .as_bytes()
+        );
     }
 }
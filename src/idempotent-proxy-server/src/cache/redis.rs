@@ -1,31 +1,103 @@
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
 use async_trait::async_trait;
 use idempotent_proxy_types::err_string;
 use rustis::bb8::{CustomizeConnection, ErrorSink, Pool};
-use rustis::client::PooledClientManager;
-use rustis::commands::{GenericCommands, SetCondition, SetExpiration, StringCommands};
+use rustis::client::{Config, PooledClientManager};
+use rustis::commands::{
+    CallBuilder, ConnectionCommands, GenericCommands, PingOptions, ScriptingCommands, SetCondition,
+    SetExpiration, StringCommands,
+};
 use rustis::resp::BulkString;
+use std::str::FromStr;
 use tokio::time::{sleep, Duration};
 
-use super::Cacher;
+use super::{CacheError, Cacher, LockToken};
+
+// Sets the value only if the key holds the lock with the token, so that a request whose
+// lock expired and was obtained again by another one can not overwrite its value.
+const SET_LOCKED_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+  redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+  return 1
+end
+return 0
+"#;
+
+// A lock is stored as `0x00 || token (u64 BE)`, values never start with a zero byte.
+fn lock_value(token: LockToken) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(9);
+    buf.push(0);
+    buf.extend_from_slice(&token.to_be_bytes());
+    buf
+}
+
+fn is_lock(val: &[u8]) -> bool {
+    val.first().is_none_or(|b| *b == 0)
+}
+
+/// Redis connection settings. The URL selects the mode:
+///
+/// - standalone: `redis[s]://[[user]:password@]host[:port][/db]` or `host:port`
+/// - cluster: `redis[s]+cluster://[[user]:password@]host1[:port1][,host2[:port2]]`
+/// - sentinel: `redis[s]+sentinel://[[user]:password@]host1[:port1][,host2[:port2]]/service[/db]`
+#[derive(Debug, Clone)]
+pub struct RedisConfig {
+    pub url: String,
+    /// Prefix of all keys, so that several proxy deployments can share one Redis.
+    pub key_prefix: String,
+    pub pool_size: u32,
+    pub min_idle: u32,
+    pub idle_timeout: Duration,
+    /// Timeout to get a connection from the pool, including connecting.
+    pub connection_timeout: Duration,
+    /// Timeout of a command, no timeout if zero.
+    pub command_timeout: Duration,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: "127.0.0.1:6379".to_string(),
+            key_prefix: String::new(),
+            pool_size: 10,
+            min_idle: 1,
+            idle_timeout: Duration::from_secs(600),
+            connection_timeout: Duration::from_secs(3),
+            command_timeout: Duration::ZERO,
+        }
+    }
+}
 
 pub struct RedisClient {
     pool: Pool<PooledClientManager>,
+    key_prefix: String,
 }
 
 impl RedisClient {
-    pub async fn new(url: &str) -> Result<Self, rustis::Error> {
-        let manager = PooledClientManager::new(url).unwrap();
+    /// Creates the client without connecting, so that a Redis outage at startup
+    /// is reported by `ping` and readiness checks instead of failing the proxy.
+    pub fn new(cfg: &RedisConfig) -> Result<Self, String> {
+        let mut config = Config::from_str(&cfg.url).map_err(err_string)?;
+        config.connect_timeout = cfg.connection_timeout;
+        config.command_timeout = cfg.command_timeout;
+        let manager = PooledClientManager::new(config).map_err(err_string)?;
         let pool = Pool::builder()
-            .max_size(10)
-            .min_idle(Some(1))
+            .max_size(cfg.pool_size)
+            .min_idle(Some(cfg.min_idle.min(cfg.pool_size)))
             .max_lifetime(None)
-            .idle_timeout(Some(Duration::from_secs(600)))
-            .connection_timeout(Duration::from_secs(3))
+            .idle_timeout(Some(cfg.idle_timeout))
+            .connection_timeout(cfg.connection_timeout)
             .error_sink(Box::new(RedisMonitor {}))
             .connection_customizer(Box::new(RedisMonitor {}))
-            .build(manager)
-            .await?;
-        Ok(RedisClient { pool })
+            .build_unchecked(manager);
+        Ok(RedisClient {
+            pool,
+            key_prefix: cfg.key_prefix.clone(),
+        })
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.key_prefix, key)
     }
 }
 
@@ -52,19 +124,21 @@ impl<C: Send + 'static, E: 'static> CustomizeConnection<C, E> for RedisMonitor {
 
 #[async_trait]
 impl Cacher for RedisClient {
-    async fn obtain(&self, key: &str, ttl: u64) -> Result<bool, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
+    async fn obtain(&self, key: &str, ttl: u64) -> Result<Option<LockToken>, CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        // random, as proxy instances share the locks
+        let token = OsRng.next_u64();
         let res = conn
             .set_with_options(
-                key,
-                BulkString::from(vec![0]),
+                self.key(key),
+                BulkString::from(lock_value(token)),
                 SetCondition::NX,
                 SetExpiration::Px(ttl),
                 false,
             )
             .await
-            .map_err(err_string)?;
-        Ok(res)
+            .map_err(CacheError::backend)?;
+        Ok(res.then_some(token))
     }
 
     async fn polling_get(
@@ -72,15 +146,16 @@ impl Cacher for RedisClient {
         key: &str,
         poll_interval: u64,
         counter: u64,
-    ) -> Result<Vec<u8>, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
+    ) -> Result<Vec<u8>, CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        let key = self.key(key);
         let mut counter = counter;
         while counter > 0 {
-            let res: Option<BulkString> = conn.get(key).await.map_err(err_string)?;
+            let res: Option<BulkString> = conn.get(&key).await.map_err(CacheError::backend)?;
             match res {
-                None => return Err("not obtained".to_string()),
+                None => return Err(CacheError::NotObtained),
                 Some(bs) => {
-                    if bs.len() > 1 {
+                    if !is_lock(&bs) {
                         return Ok(bs.into());
                     }
                 }
@@ -90,27 +165,117 @@ impl Cacher for RedisClient {
             sleep(Duration::from_millis(poll_interval)).await;
         }
 
-        Err(("polling get cache timeout").to_string())
+        Err(CacheError::Timeout)
     }
 
-    async fn set(&self, key: &str, val: Vec<u8>, ttl: u64) -> Result<bool, String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let res = conn
-            .set_with_options(
-                key,
-                BulkString::from(val),
-                SetCondition::XX,
-                SetExpiration::Px(ttl),
-                false,
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        let res: Option<BulkString> = conn.get(self.key(key)).await.map_err(CacheError::backend)?;
+        Ok(res.filter(|bs| !is_lock(bs)).map(|bs| bs.into()))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        token: LockToken,
+        val: Vec<u8>,
+        ttl: u64,
+    ) -> Result<bool, CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        let res: i64 = conn
+            .eval(
+                CallBuilder::script(SET_LOCKED_SCRIPT)
+                    .keys(self.key(key))
+                    .args(vec![
+                        BulkString::from(lock_value(token)),
+                        BulkString::from(val),
+                        BulkString::from(ttl.to_string().into_bytes()),
+                    ]),
             )
             .await
-            .map_err(err_string)?;
-        Ok(res)
+            .map_err(CacheError::backend)?;
+        Ok(res == 1)
     }
 
-    async fn del(&self, key: &str) -> Result<(), String> {
-        let conn = self.pool.get().await.map_err(err_string)?;
-        let _ = conn.del(key).await.map_err(err_string)?;
+    async fn del(&self, key: &str) -> Result<(), CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        let _ = conn.del(self.key(key)).await.map_err(CacheError::backend)?;
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), CacheError> {
+        let conn = self.pool.get().await.map_err(CacheError::backend)?;
+        let _: String = conn
+            .ping(PingOptions::default())
+            .await
+            .map_err(CacheError::backend)?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::conformance;
+    use std::process::{Child, Command, Stdio};
+
+    // A redis-server process for the test, killed on drop.
+    struct RedisServer(Child);
+
+    impl Drop for RedisServer {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    // Starts a local redis-server without persistence, None if it is not installed.
+    fn start_redis() -> Option<(RedisServer, String)> {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .ok()?
+            .local_addr()
+            .ok()?
+            .port();
+        let child = Command::new("redis-server")
+            .args([
+                "--port",
+                &port.to_string(),
+                "--save",
+                "",
+                "--appendonly",
+                "no",
+            ])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+        Some((RedisServer(child), format!("127.0.0.1:{}", port)))
+    }
+
+    #[tokio::test]
+    async fn redis_client_conformance() {
+        let Some((_server, url)) = start_redis() else {
+            println!("redis-server is not available, skipping");
+            return;
+        };
+
+        let rc = RedisClient::new(&RedisConfig {
+            url,
+            key_prefix: "test:".to_string(),
+            connection_timeout: Duration::from_secs(1),
+            ..Default::default()
+        })
+        .unwrap();
+        let mut ready = false;
+        for _ in 0..50 {
+            if rc.ping().await.is_ok() {
+                ready = true;
+                break;
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+        assert!(ready, "redis-server is not ready");
+
+        conformance::run(&rc, &conformance::TestTime::Real).await;
+    }
+}
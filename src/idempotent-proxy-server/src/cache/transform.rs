@@ -0,0 +1,77 @@
+use ciborium::Value;
+use idempotent_proxy_types::err_string;
+use jmespath::Expression;
+use std::{fmt, sync::Arc};
+
+/// A compiled JMESPath expression that reshapes JSON or CBOR response bodies.
+/// CBOR bodies are evaluated in the JSON data model, so byte strings become arrays of numbers.
+#[derive(Clone)]
+pub struct JsonTransform(Arc<Expression<'static>>);
+
+impl JsonTransform {
+    pub fn compile(expr: &str) -> Result<Self, String> {
+        jmespath::compile(expr)
+            .map(|expr| Self(Arc::new(expr)))
+            .map_err(|err| format!("invalid json transform: {}", err))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+
+    pub fn apply_json(&self, value: &serde_json::Value) -> Result<serde_json::Value, String> {
+        let rt = self.0.search(value).map_err(err_string)?;
+        serde_json::to_value(&rt).map_err(err_string)
+    }
+
+    pub fn apply_cbor(&self, value: &Value) -> Result<Value, String> {
+        let rt = self.0.search(value).map_err(err_string)?;
+        Value::serialized(&rt).map_err(err_string)
+    }
+}
+
+impl fmt::Debug for JsonTransform {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("JsonTransform")
+            .field(&self.as_str())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ciborium::cbor;
+    use serde_json::json;
+
+    #[test]
+    fn test_json_transform() {
+        assert!(JsonTransform::compile("result.[").is_err());
+
+        let tf = JsonTransform::compile("result.transactions[*].hash").unwrap();
+        let body = json!({
+            "id": 1,
+            "result": {
+                "transactions": [
+                    {"hash": "0x01", "value": "0x10"},
+                    {"hash": "0x02", "value": "0x20"},
+                ],
+            },
+        });
+        assert_eq!(tf.apply_json(&body).unwrap(), json!(["0x01", "0x02"]));
+
+        let body = cbor!({
+            "result" => {
+                "transactions" => [{"hash" => "0x01"}, {"hash" => "0x02"}],
+            },
+        })
+        .unwrap();
+        assert_eq!(
+            tf.apply_cbor(&body).unwrap(),
+            cbor!(["0x01", "0x02"]).unwrap()
+        );
+
+        let tf = JsonTransform::compile("missing").unwrap();
+        assert_eq!(tf.apply_json(&json!({"id": 1})).unwrap(), json!(null));
+    }
+}
@@ -0,0 +1,235 @@
+use http::{header::HeaderName, HeaderMap, HeaderValue};
+use idempotent_proxy_types::*;
+use std::{collections::BTreeSet, collections::HashMap, str::FromStr};
+
+// Headers that are never forwarded to upstreams.
+static PRIVATE_HEADERS: [&HeaderName; 2] = [&http::header::HOST, &HEADER_PROXY_AUTHORIZATION];
+
+// Headers that control the proxy, only forwarded if named in an allow policy.
+static CONTROL_HEADERS: [&HeaderName; 11] = [
+    &http::header::FORWARDED,
+    &HEADER_X_FORWARDED_FOR,
+    &HEADER_X_FORWARDED_HOST,
+    &HEADER_X_FORWARDED_PROTO,
+    &HEADER_IDEMPOTENCY_KEY,
+    &HEADER_X_JSON_MASK,
+    &HEADER_X_JSON_TRANSFORM,
+    &HEADER_X_JSON_CANONICAL,
+    &HEADER_RESPONSE_HEADERS,
+    &HEADER_MAX_RESPONSE_BYTES,
+    &HEADER_X_PROXY_SIGNATURE,
+];
+
+/// Which client headers are forwarded to an upstream, parsed from
+/// "allow:name1,name2" or "deny:name1,name2".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderPolicy {
+    Allow(BTreeSet<String>),
+    Deny(BTreeSet<String>),
+}
+
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        Self::Deny(BTreeSet::new())
+    }
+}
+
+impl HeaderPolicy {
+    pub fn forwards(&self, name: &str) -> bool {
+        match self {
+            Self::Allow(names) => names.contains(name),
+            Self::Deny(names) => !names.contains(name),
+        }
+    }
+
+    /// Returns true if the header is explicitly named in an allow policy.
+    pub fn allows(&self, name: &str) -> bool {
+        matches!(self, Self::Allow(names) if names.contains(name))
+    }
+}
+
+impl FromStr for HeaderPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, names) = s
+            .trim()
+            .split_once(':')
+            .ok_or_else(|| format!("invalid header policy: {}", s))?;
+        let names: BTreeSet<String> = names
+            .split(',')
+            .map(|v| v.trim().to_ascii_lowercase())
+            .filter(|v| !v.is_empty())
+            .collect();
+        for name in &names {
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| format!("invalid header name: {}", name))?;
+        }
+        match kind.trim() {
+            "allow" => Ok(Self::Allow(names)),
+            "deny" => Ok(Self::Deny(names)),
+            _ => Err(format!("invalid header policy: {}", s)),
+        }
+    }
+}
+
+/// Forwarding policy of the client headers, with per-route overrides.
+/// A route is a `URL_*` var name or an upstream host.
+#[derive(Debug, Clone)]
+pub struct ForwardPolicy {
+    default: HeaderPolicy,
+    routes: HashMap<String, HeaderPolicy>,
+    user_agent: HeaderValue,
+}
+
+impl ForwardPolicy {
+    pub fn new(default: HeaderPolicy, user_agent: HeaderValue) -> Self {
+        Self {
+            default,
+            routes: HashMap::new(),
+            user_agent,
+        }
+    }
+
+    pub fn with_route(mut self, route: &str, policy: HeaderPolicy) -> Self {
+        self.routes.insert(Self::route_key(route), policy);
+        self
+    }
+
+    /// Returns the key of a route, as used in `FORWARD_HEADERS_<KEY>` env vars:
+    /// uppercased, with characters other than ASCII letters and digits replaced by `_`.
+    /// e.g. "URL_HTTPBIN" or "HTTPBIN_ORG" for host "httpbin.org".
+    pub fn route_key(route: &str) -> String {
+        route
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    pub fn policy(&self, route: &str) -> &HeaderPolicy {
+        self.routes
+            .get(&Self::route_key(route))
+            .unwrap_or(&self.default)
+    }
+
+    /// Removes the headers not forwarded to the route, and sets the proxy `User-Agent`.
+    /// The control headers and `User-Agent` of the client are only forwarded
+    /// if the policy of the route allows them by name.
+    pub fn apply(&self, route: &str, headers: &mut HeaderMap) {
+        for name in PRIVATE_HEADERS {
+            headers.remove(name);
+        }
+
+        let policy = self.policy(route);
+        for name in CONTROL_HEADERS {
+            if !policy.allows(name.as_str()) {
+                headers.remove(name);
+            }
+        }
+
+        let denied: Vec<HeaderName> = headers
+            .keys()
+            .filter(|k| !policy.forwards(k.as_str()))
+            .cloned()
+            .collect();
+        for name in denied {
+            headers.remove(name);
+        }
+
+        if !policy.allows(http::header::USER_AGENT.as_str())
+            || !headers.contains_key(http::header::USER_AGENT)
+        {
+            headers.insert(http::header::USER_AGENT, self.user_agent.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_forward_policy() {
+        assert!("allow".parse::<HeaderPolicy>().is_err());
+        assert!("block:accept".parse::<HeaderPolicy>().is_err());
+        assert!("deny:bad header".parse::<HeaderPolicy>().is_err());
+
+        let policy = ForwardPolicy::new(
+            "deny: Cookie".parse().unwrap(),
+            HeaderValue::from_static("idempotent-proxy"),
+        )
+        .with_route(
+            "URL_HTTPBIN",
+            "allow:accept,content-type,authorization".parse().unwrap(),
+        )
+        .with_route("api.example.com", "deny:".parse().unwrap());
+        assert_eq!(
+            ForwardPolicy::route_key("api.example.com"),
+            "API_EXAMPLE_COM"
+        );
+
+        let mut headers = HeaderMap::new();
+        for (k, v) in [
+            ("accept", "application/json"),
+            ("authorization", "HEADER_API_TOKEN"),
+            ("cookie", "a=1"),
+            ("x-custom", "1"),
+            ("user-agent", "ic/1.0"),
+            ("idempotency-key", "key001"),
+            ("x-json-mask", "args"),
+            ("response-headers", "date"),
+            ("x-forwarded-host", "httpbin.org"),
+            ("proxy-authorization", "Bearer xxx"),
+        ] {
+            headers.insert(HeaderName::from_static(k), HeaderValue::from_static(v));
+        }
+
+        let mut h = headers.clone();
+        policy.apply("URL_HTTPBIN", &mut h);
+        let mut names: Vec<&str> = h.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["accept", "authorization", "user-agent"]);
+        assert_eq!(h.get("user-agent").unwrap(), "idempotent-proxy");
+
+        let mut h = headers.clone();
+        policy.apply("httpbin.org", &mut h);
+        let mut names: Vec<&str> = h.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["accept", "authorization", "user-agent", "x-custom"]
+        );
+
+        let mut h = headers.clone();
+        policy.apply("api.example.com", &mut h);
+        assert!(h.contains_key("cookie"));
+        assert!(!h.contains_key("idempotency-key"));
+        assert_eq!(h.get("user-agent").unwrap(), "idempotent-proxy");
+
+        // an allow policy re-enables the control headers and user-agent it names
+        let policy = policy.with_route(
+            "URL_PAYMENT",
+            "allow:accept,idempotency-key,user-agent,proxy-authorization"
+                .parse()
+                .unwrap(),
+        );
+        let mut h = headers.clone();
+        policy.apply("URL_PAYMENT", &mut h);
+        let mut names: Vec<&str> = h.keys().map(|k| k.as_str()).collect();
+        names.sort();
+        assert_eq!(names, vec!["accept", "idempotency-key", "user-agent"]);
+        assert_eq!(h.get("idempotency-key").unwrap(), "key001");
+        assert_eq!(h.get("user-agent").unwrap(), "ic/1.0");
+
+        let mut h = headers.clone();
+        h.remove("user-agent");
+        policy.apply("URL_PAYMENT", &mut h);
+        assert_eq!(h.get("user-agent").unwrap(), "idempotent-proxy");
+    }
+}
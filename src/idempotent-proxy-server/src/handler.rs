@@ -2,38 +2,55 @@ use axum::{
     body::to_bytes,
     extract::{Request, State},
     response::IntoResponse,
+    Json,
 };
 use base64::{engine::general_purpose, Engine};
-use http::{header::AsHeaderName, HeaderMap, HeaderValue, StatusCode};
-use idempotent_proxy_types::*;
-use k256::ecdsa;
+use http::{header::AsHeaderName, Extensions, HeaderMap, HeaderValue, StatusCode};
+use idempotent_proxy_types::{
+    auth::{Claims, KeyRegistry},
+    clock::Clock,
+    icp::IcpToken,
+    *,
+};
 use reqwest::Client;
 use std::{
     collections::{BTreeSet, HashMap},
     sync::Arc,
 };
 
-use crate::cache::{Cacher, HybridCacher, ResponseData};
+use crate::{
+    cache::{
+        BodyFormat, BodyOptions, Cacher, Canonicalizer, HybridCacher, JsonMask, JsonTransform,
+        LockToken, ResponseCodec, ResponseData, ResponseSigner, SecretScrubber,
+    },
+    forward::ForwardPolicy,
+    revocation::{self, Revocation},
+    tls::ClientIdentity,
+};
 
 #[derive(Clone)]
 pub struct AppState {
     pub http_client: Arc<Client>,
     pub cacher: Arc<HybridCacher>,
+    pub codec: Arc<ResponseCodec>,
+    pub signer: Option<Arc<ResponseSigner>>,
     pub agents: Arc<BTreeSet<String>>,
+    pub admin_agents: Arc<BTreeSet<String>>,
     pub url_vars: Arc<HashMap<String, String>>,
     pub header_vars: Arc<HashMap<String, HeaderValue>>,
-    pub ecdsa_pub_keys: Arc<Vec<ecdsa::VerifyingKey>>,
-    pub ed25519_pub_keys: Arc<Vec<ed25519_dalek::VerifyingKey>>,
+    pub transforms: Arc<HashMap<String, JsonTransform>>,
+    pub canonicalizer: Arc<Canonicalizer>,
+    pub scrubber: Arc<SecretScrubber>,
+    pub forward: Arc<ForwardPolicy>,
+    pub keys: Arc<KeyRegistry>,
+    pub audience: Arc<String>, // proxy identifier, tokens must be issued for it if not empty
+    pub ic_root_key: Arc<Vec<u8>>, // raw IC root public key, enables ICP tokens if not empty
+    pub clock: Arc<dyn Clock>,
 }
 
 impl AppState {
-    pub fn alter_headers(&self, headers: &mut HeaderMap) {
-        headers.remove(&http::header::HOST);
-        headers.remove(&http::header::FORWARDED);
-        headers.remove(&HEADER_PROXY_AUTHORIZATION);
-        headers.remove(&HEADER_X_FORWARDED_FOR);
-        headers.remove(&HEADER_X_FORWARDED_HOST);
-        headers.remove(&HEADER_X_FORWARDED_PROTO);
+    pub fn alter_headers(&self, route: &str, headers: &mut HeaderMap) {
+        self.forward.apply(route, headers);
 
         if !self.header_vars.is_empty() {
             for val in headers.values_mut() {
@@ -46,27 +63,239 @@ impl AppState {
         }
     }
 
-    // TODO: support JWT and CWT
-    pub fn verify_token(&self, access_token: &str) -> Result<String, String> {
-        if !access_token.starts_with("Bearer ") {
-            return Err("invalid proxy-authorization header".to_string());
+    // Returns the named transform if the header value is a TRANSFORM_* name,
+    // or compiles it as a JMESPath expression.
+    pub fn json_transform(&self, expr: &str) -> Result<Option<JsonTransform>, String> {
+        if expr.is_empty() {
+            return Ok(None);
         }
+        if expr.starts_with("TRANSFORM_") {
+            return self
+                .transforms
+                .get(expr)
+                .cloned()
+                .map(Some)
+                .ok_or_else(|| format!("unknown json transform: {}", expr));
+        }
+        JsonTransform::compile(expr).map(Some)
+    }
+
+    // TODO: support JWT and CWT
+    // Returns the claims and the hash of the verified token.
+    pub fn verify_token(&self, access_token: &str) -> Result<(Claims, String), String> {
+        let (scheme, token) = access_token
+            .split_once(' ')
+            .ok_or("invalid proxy-authorization header")?;
         let token = general_purpose::URL_SAFE_NO_PAD
-            .decode(access_token.strip_prefix("Bearer ").unwrap().as_bytes())
+            .decode(token.as_bytes())
             .map_err(|err| err.to_string())?;
-        if !self.ecdsa_pub_keys.is_empty() {
-            return auth::ecdsa_verify(&self.ecdsa_pub_keys, &token)
-                .map(|t| t.1)
-                .map_err(|err| format!("proxy authentication verify failed: {}", err));
+        let claims = match scheme {
+            "Bearer" if !self.keys.is_empty() => {
+                self.keys.verify_with_clock(&token, self.clock.as_ref())
+            }
+            "ICP" if !self.ic_root_key.is_empty() => {
+                IcpToken::verify_with_clock(&token, &self.ic_root_key, self.clock.as_ref())
+            }
+            _ => return Err("invalid proxy-authorization header".to_string()),
+        }
+        .map_err(|err| format!("proxy authentication verify failed: {}", err))?;
+
+        if !self.audience.is_empty() && claims.audience != *self.audience {
+            return Err(format!(
+                "proxy authentication verify failed: token audience {:?} mismatch",
+                claims.audience
+            ));
+        }
+        Ok((claims, revocation::token_hash(&token)))
+    }
+
+    pub async fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        extensions: &Extensions,
+    ) -> Result<Claims, (StatusCode, String)> {
+        let identity = extensions
+            .get::<Option<ClientIdentity>>()
+            .cloned()
+            .flatten();
+        let (claims, token_hash) = if let Some(identity) = identity {
+            // authenticated by a client certificate through mutual TLS
+            let claims = Claims {
+                agent: identity.agent,
+                ..Default::default()
+            };
+            (claims, None)
+        } else if !self.keys.is_empty() || !self.ic_root_key.is_empty() {
+            let token = extract_header(headers, &HEADER_PROXY_AUTHORIZATION, || "".to_string());
+
+            match self.verify_token(&token) {
+                Err(err) => return Err((StatusCode::PROXY_AUTHENTICATION_REQUIRED, err)),
+                Ok((claims, token_hash)) => (claims, Some(token_hash)),
+            }
+        } else {
+            return Ok(Claims {
+                agent: "ANON".to_string(),
+                ..Default::default()
+            });
+        };
+
+        if let Some(reason) = revocation::check(&self.cacher, &claims, token_hash.as_deref())
+            .await
+            .map_err(bad_gateway)?
+        {
+            return Err((
+                StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+                format!("proxy authentication verify failed: {}", reason),
+            ));
         }
-        if !self.ed25519_pub_keys.is_empty() {
-            return auth::ed25519_verify(&self.ed25519_pub_keys, &token)
-                .map(|t| t.1)
-                .map_err(|err| format!("proxy authentication verify failed: {}", err));
+        Ok(claims)
+    }
+
+    async fn revocation_request(
+        &self,
+        req: Request,
+    ) -> Result<(String, Revocation), (StatusCode, String)> {
+        let claims = self.authenticate(req.headers(), req.extensions()).await?;
+        if !self.admin_agents.contains(&claims.agent) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("agent {} is not an admin", claims.agent),
+            ));
         }
 
-        Err("proxy authentication verify failed".to_string())
+        let body = to_bytes(req.into_body(), 1024 * 1024)
+            .await
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        let revocation: Revocation = serde_json::from_slice(&body)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Ok((claims.agent, revocation))
     }
+
+    // Requests the upstream and builds the response to cache, or returns the error
+    // of a response that is not cached.
+    async fn fetch_response(
+        &self,
+        req: Request,
+        route: &str,
+        url: reqwest::Url,
+        request_key: &str,
+        body_opts: &BodyOptions,
+        max_response_bytes: Option<usize>,
+    ) -> Result<ResponseData, (StatusCode, String)> {
+        let method = req.method();
+        let response_headers =
+            extract_header(req.headers(), &HEADER_RESPONSE_HEADERS, || "".to_string());
+
+        let mut headers = req.headers().clone();
+        self.alter_headers(route, &mut headers);
+        if body_opts.format == Some(BodyFormat::Cbor) {
+            // the proxy converts JSON to the CBOR accepted by the client,
+            // other clients' Accept is forwarded unchanged
+            headers.insert(
+                http::header::ACCEPT,
+                HeaderValue::from_static("application/json, application/cbor"),
+            );
+        }
+
+        let mut rreq = reqwest::Request::new(method.clone(), url);
+        *rreq.headers_mut() = headers;
+
+        if !method.is_safe() {
+            let body = to_bytes(req.into_body(), 1024 * 1024)
+                .await
+                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+            *rreq.body_mut() = Some(reqwest::Body::from(body));
+        }
+
+        let rres = self.http_client.execute(rreq).await.map_err(bad_gateway)?;
+        let status = rres.status();
+        let headers = rres.headers().to_owned();
+        let res_body = rres.bytes().await.map_err(bad_gateway)?;
+
+        // If the HTTP status code is 500 or below, it's considered a server response and should be cached; any exceptions should be handled by the client. Otherwise, it's considered a non-response from the server and should not be cached.
+        if status >= StatusCode::OK && status <= StatusCode::INTERNAL_SERVER_ERROR {
+            let mut rd = ResponseData::new(status.as_u16());
+            rd.with_headers(&headers, &response_headers);
+            rd.with_body(&res_body, body_opts).map_err(bad_gateway)?;
+            rd.scrub(&self.scrubber).map_err(bad_gateway)?;
+            let sign = |rd: &mut ResponseData| {
+                if let Some(signer) = &self.signer {
+                    rd.sign(signer, request_key, self.clock.as_ref());
+                }
+            };
+            sign(&mut rd);
+            if let Some(max) = max_response_bytes {
+                rd.limit_size(max, sign);
+            }
+            Ok(rd)
+        } else {
+            let res_body = self
+                .scrubber
+                .scrub_bytes(&res_body)
+                .unwrap_or_else(|| res_body.to_vec());
+            Err((status, String::from_utf8_lossy(&res_body).to_string()))
+        }
+    }
+
+    // Returns false if the lock was taken over by another request, which stores its response.
+    async fn store_response(
+        &self,
+        idempotency_key: &str,
+        token: LockToken,
+        rd: &ResponseData,
+    ) -> Result<bool, (StatusCode, String)> {
+        let data = self
+            .codec
+            .encode(idempotency_key, rd)
+            .map_err(bad_gateway)?;
+        self.cacher
+            .set(idempotency_key, token, data, self.cacher.cache_ttl)
+            .await
+            .map_err(bad_gateway)
+    }
+}
+
+/// Adds a token or agent revocation.
+pub async fn add_revocation(
+    State(app): State<AppState>,
+    req: Request,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (admin, mut revocation) = app.revocation_request(req).await?;
+    revocation
+        .save(&app.cacher, app.clock.as_ref())
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    log::warn!(target: "handler",
+                action = "revoke",
+                admin = admin;
+                "{:?}", revocation);
+    Ok(Json(revocation))
+}
+
+/// Removes a token or agent revocation.
+pub async fn remove_revocation(
+    State(app): State<AppState>,
+    req: Request,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let (admin, revocation) = app.revocation_request(req).await?;
+    revocation
+        .remove(&app.cacher)
+        .await
+        .map_err(|err| (StatusCode::BAD_REQUEST, err))?;
+    log::warn!(target: "handler",
+                action = "unrevoke",
+                admin = admin;
+                "{:?}", revocation);
+    Ok(Json(revocation))
+}
+
+/// Readiness check, returns 503 if the cacher backend is unreachable.
+pub async fn ready(State(app): State<AppState>) -> Result<&'static str, (StatusCode, String)> {
+    app.cacher
+        .ping()
+        .await
+        .map_err(|err| (StatusCode::SERVICE_UNAVAILABLE, err.to_string()))?;
+    Ok("ok")
 }
 
 pub async fn proxy(
@@ -74,18 +303,8 @@ pub async fn proxy(
     req: Request,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
     // Access control
-    let agent = if !app.ecdsa_pub_keys.is_empty() || !app.ed25519_pub_keys.is_empty() {
-        let token = extract_header(req.headers(), &HEADER_PROXY_AUTHORIZATION, || {
-            "".to_string()
-        });
-
-        match app.verify_token(&token) {
-            Err(err) => return Err((StatusCode::PROXY_AUTHENTICATION_REQUIRED, err)),
-            Ok(agent) => agent,
-        }
-    } else {
-        "ANON".to_string()
-    };
+    let claims = app.authenticate(req.headers(), req.extensions()).await?;
+    let agent = claims.agent.clone();
 
     if !app.agents.is_empty() && !app.agents.contains(&agent) {
         return Err((
@@ -96,17 +315,25 @@ pub async fn proxy(
 
     let method = req.method().to_string();
     let path = req.uri().path();
-    let url = if path.starts_with("/URL_") {
+    let (route, url) = if path.starts_with("/URL_") {
+        let name = path.strip_prefix('/').unwrap();
+        if !claims.allows_url_var(name) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("agent {} is not allowed to access {}", agent, name),
+            ));
+        }
+
         let url = app
             .url_vars
-            .get(path.strip_prefix('/').unwrap())
+            .get(name)
             .map(|s| s.to_string())
             .unwrap_or_default();
         if !url.starts_with("http") {
             return Err((StatusCode::BAD_REQUEST, format!("invalid url: {}", url)));
         }
 
-        url
+        (name.to_string(), url)
     } else {
         let host = extract_header(req.headers(), &HEADER_X_FORWARDED_HOST, || "".to_string());
         if host.is_empty() {
@@ -115,13 +342,20 @@ pub async fn proxy(
                 "missing header: x-forwarded-host".to_string(),
             ));
         }
+        if !claims.allows_host(&host) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("agent {} is not allowed to access {}", agent, host),
+            ));
+        }
 
         let path_query = req
             .uri()
             .path_and_query()
             .map(|v| v.as_str())
             .unwrap_or(path);
-        format!("https://{}{}", host, path_query)
+        let url = format!("https://{}{}", host, path_query);
+        (host, url)
     };
 
     let url =
@@ -134,25 +368,78 @@ pub async fn proxy(
         ));
     }
 
-    let idempotency_key = format!("{}:{}:{}", agent, method, idempotency_key);
+    let json_mask = extract_header(req.headers(), &HEADER_X_JSON_MASK, || "".to_string());
+    let json_transform = extract_header(req.headers(), &HEADER_X_JSON_TRANSFORM, || "".to_string());
+    let canonical =
+        match extract_header(req.headers(), &HEADER_X_JSON_CANONICAL, || "".to_string()).as_str() {
+            "" | "false" | "0" => None,
+            "true" | "1" => Some(Canonicalizer::clone(&app.canonicalizer)),
+            v => {
+                return Err((
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid x-json-canonical header: {}", v),
+                ))
+            }
+        };
+    let body_opts = BodyOptions {
+        mask: JsonMask::parse(&json_mask).map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        transform: app
+            .json_transform(&json_transform)
+            .map_err(|err| (StatusCode::BAD_REQUEST, err))?,
+        canonical,
+        format: extract_header(req.headers(), http::header::ACCEPT, || "".to_string())
+            .split(',')
+            .find_map(|v| BodyFormat::from_mime(v.trim())),
+    };
+
+    let max_response_bytes =
+        match extract_header(req.headers(), &HEADER_MAX_RESPONSE_BYTES, || "".to_string()) {
+            v if v.is_empty() => None,
+            v => Some(v.parse::<usize>().map_err(|_| {
+                (
+                    StatusCode::BAD_REQUEST,
+                    format!("invalid max-response-bytes header: {}", v),
+                )
+            })?),
+        };
 
-    let lock = app
-        .cacher
-        .obtain(&idempotency_key, app.cacher.cache_ttl)
-        .await
-        .map_err(bad_gateway)?;
-    if !lock {
-        let data = app
+    let request_key = idempotency_key;
+    let idempotency_key = format!("{}:{}:{}", agent, method, request_key);
+    if revocation::is_reserved_key(&idempotency_key) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("reserved idempotency key: {}", idempotency_key),
+        ));
+    }
+
+    // a completed response in L1 needs no lock
+    let cached = app.cacher.l1_get(&idempotency_key);
+    let lock = match cached {
+        Some(_) => None,
+        None => app
             .cacher
-            .polling_get(
-                &idempotency_key,
-                app.cacher.poll_interval,
-                app.cacher.cache_ttl / app.cacher.poll_interval,
-            )
+            .obtain(&idempotency_key, app.cacher.cache_ttl)
             .await
-            .map_err(bad_gateway)?;
+            .map_err(bad_gateway)?,
+    };
+    let Some(token) = lock else {
+        let data = match cached {
+            Some(data) => data,
+            None => app
+                .cacher
+                .polling_get(
+                    &idempotency_key,
+                    app.cacher.poll_interval,
+                    app.cacher.cache_ttl / app.cacher.poll_interval,
+                )
+                .await
+                .map_err(bad_gateway)?,
+        };
 
-        let res = ResponseData::try_from(&data[..]).map_err(bad_gateway)?;
+        let res = app
+            .codec
+            .decode(&idempotency_key, &data)
+            .map_err(bad_gateway)?;
         log::info!(target: "handler",
                     action = "cachehit",
                     method = method,
@@ -162,49 +449,35 @@ pub async fn proxy(
                     idempotency_key = idempotency_key;
                     "");
         return Ok(res);
-    }
-
-    let res = {
-        let method = req.method();
-        let json_mask = extract_header(req.headers(), &HEADER_X_JSON_MASK, || "".to_string());
-        let response_headers =
-            extract_header(req.headers(), &HEADER_RESPONSE_HEADERS, || "".to_string());
-
-        let mut headers = req.headers().clone();
-        app.alter_headers(&mut headers);
-
-        let mut rreq = reqwest::Request::new(method.clone(), url.clone());
-        *rreq.headers_mut() = headers;
-
-        if !method.is_safe() {
-            let body = to_bytes(req.into_body(), 1024 * 1024)
-                .await
-                .map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
-            *rreq.body_mut() = Some(reqwest::Body::from(body));
-        }
-
-        let rres = app.http_client.execute(rreq).await.map_err(bad_gateway)?;
-        let status = rres.status();
-        let headers = rres.headers().to_owned();
-        let res_body = rres.bytes().await.map_err(bad_gateway)?;
-
-        // If the HTTP status code is 500 or below, it's considered a server response and should be cached; any exceptions should be handled by the client. Otherwise, it's considered a non-response from the server and should not be cached.
-        if status >= StatusCode::OK && status <= StatusCode::INTERNAL_SERVER_ERROR {
-            let mut rd = ResponseData::new(status.as_u16());
-            rd.with_headers(&headers, &response_headers);
-            rd.with_body(&res_body, &json_mask).map_err(bad_gateway)?;
-            let data = rd.to_bytes().map_err(bad_gateway)?;
-
-            let _ = app
-                .cacher
-                .set(&idempotency_key, data, app.cacher.cache_ttl)
-                .await
-                .map_err(bad_gateway)?;
+    };
 
-            Ok(rd)
-        } else {
-            Err((status, String::from_utf8_lossy(&res_body).to_string()))
-        }
+    // the lock is released on any error, for the request to be retried
+    let res = match app
+        .fetch_response(
+            req,
+            &route,
+            url.clone(),
+            &request_key,
+            &body_opts,
+            max_response_bytes,
+        )
+        .await
+    {
+        Ok(rd) => match app.store_response(&idempotency_key, token, &rd).await {
+            Ok(true) => Ok(rd),
+            Ok(false) => {
+                log::warn!(target: "handler",
+                    action = "proxying",
+                    method = method,
+                    url = url.to_string(),
+                    agent = agent,
+                    idempotency_key = idempotency_key;
+                    "lock expired before the response was stored");
+                Ok(rd)
+            }
+            Err(err) => Err(err),
+        },
+        Err(err) => Err(err),
     };
 
     match res {
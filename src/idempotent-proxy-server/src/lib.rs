@@ -0,0 +1,13 @@
+//! The idempotent proxy as a library, to embed it in other axum services.
+//! See `ProxyBuilder`.
+
+pub mod cache;
+pub mod forward;
+pub mod handler;
+pub mod revocation;
+pub mod tls;
+
+mod builder;
+
+pub use builder::*;
+pub use idempotent_proxy_types as types;
@@ -1,8 +1,16 @@
-use axum::{routing, Router};
-use axum_server::tls_rustls::RustlsConfig;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
 use base64::{engine::general_purpose, Engine};
 use dotenvy::dotenv;
 use http::HeaderValue;
+use idempotent_proxy_server::{
+    cache::{self, Cacher},
+    forward, tls, ProxyBuilder, APP_NAME, APP_VERSION,
+};
+use idempotent_proxy_types::{
+    auth::{KeyInfo, KeyRegistry, PublicKey, SigningKey},
+    clock::{Clock, SystemClock},
+    icp,
+};
 use k256::ecdsa;
 use reqwest::ClientBuilder;
 use std::{
@@ -12,13 +20,7 @@ use std::{
     time::Duration,
 };
 use structured_logger::{async_json::new_writer, get_env_level, Builder};
-use tokio::signal;
-
-mod cache;
-mod handler;
-
-const APP_NAME: &str = env!("CARGO_PKG_NAME");
-const APP_VERSION: &str = env!("CARGO_PKG_VERSION");
+use tokio::{signal, task::JoinHandle};
 
 #[tokio::main]
 async fn main() {
@@ -28,6 +30,11 @@ async fn main() {
         .with_target_writer("*", new_writer(tokio::io::stdout()))
         .init();
 
+    // both ring and aws-lc-rs are enabled by dependencies, rustls can not pick one
+    rustls::crypto::ring::default_provider()
+        .install_default()
+        .expect("failed to install rustls crypto provider");
+
     let req_timeout: u64 = std::env::var("REQUEST_TIMEOUT")
         .map(|n| n.parse().unwrap())
         .unwrap_or(10000u64)
@@ -47,26 +54,111 @@ async fn main() {
         .build()
         .unwrap();
 
-    let cacher_entry = match std::env::var("REDIS_URL") {
+    let mut sled_cacher: Option<cache::SledCacher> = None;
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let mut memory_snapshot: Option<(cache::MemoryCacher, String, Option<JoinHandle<()>>)> = None;
+    let backend: Box<dyn Cacher> = match std::env::var("REDIS_URL") {
         Ok(url) => {
-            let redis_client = cache::RedisClient::new(&url).await.unwrap();
-            cache::CacherEntry::Redis(redis_client)
+            let default = cache::RedisConfig::default();
+            let cfg = cache::RedisConfig {
+                url,
+                key_prefix: std::env::var("REDIS_KEY_PREFIX").unwrap_or_default(),
+                pool_size: env_number("REDIS_POOL_SIZE").unwrap_or(default.pool_size),
+                min_idle: env_number("REDIS_MIN_IDLE").unwrap_or(default.min_idle),
+                idle_timeout: env_number("REDIS_IDLE_TIMEOUT")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.idle_timeout),
+                connection_timeout: env_number("REDIS_CONNECTION_TIMEOUT")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.connection_timeout),
+                command_timeout: env_number("REDIS_COMMAND_TIMEOUT")
+                    .map(Duration::from_millis)
+                    .unwrap_or(default.command_timeout),
+            };
+            let redis_client =
+                cache::RedisClient::new(&cfg).unwrap_or_else(|err| panic!("REDIS_URL: {}", err));
+            Box::new(redis_client)
         }
-        Err(_) => cache::CacherEntry::Memory(cache::MemoryCacher::default()),
+        Err(_) => match std::env::var("SLED_PATH") {
+            Ok(path) => {
+                let cacher = cache::SledCacher::open(&path)
+                    .unwrap_or_else(|err| panic!("SLED_PATH: {}", err))
+                    .with_clock(clock.clone());
+                cacher.spawn_compaction(Duration::from_secs(60));
+                sled_cacher = Some(cacher.clone());
+                Box::new(cacher)
+            }
+            Err(_) => {
+                let cacher = cache::MemoryCacher::new(
+                    env_number("MEMORY_CACHE_MAX_ENTRIES").unwrap_or(usize::MAX),
+                    env_number("MEMORY_CACHE_MAX_BYTES").unwrap_or(usize::MAX),
+                )
+                .with_clock(clock.clone());
+                cacher.spawn_sweeper(Duration::from_secs(1));
+                if let Ok(path) = std::env::var("MEMORY_SNAPSHOT_PATH") {
+                    let n = cacher
+                        .restore(&path)
+                        .unwrap_or_else(|err| panic!("MEMORY_SNAPSHOT_PATH: {}", err));
+                    log::warn!(target: "server", "restored {} entries from {}", n, path);
+                    let interval = env_number("MEMORY_SNAPSHOT_INTERVAL").unwrap_or(60000u64);
+                    let task = (interval > 0).then(|| {
+                        cacher.spawn_snapshots(path.clone(), Duration::from_millis(interval))
+                    });
+                    memory_snapshot = Some((cacher.clone(), path, task));
+                }
+                Box::new(cacher)
+            }
+        },
     };
 
-    let agents: BTreeSet<String> = std::env::var("ALLOW_AGENTS")
-        .unwrap_or_default()
-        .split(',')
-        .filter_map(|s| {
-            let s = s.trim();
-            if s.is_empty() {
-                None
-            } else {
-                Some(s.to_string())
-            }
-        })
-        .collect();
+    let mut cacher = cache::HybridCacher::new(poll_interval, req_timeout, backend);
+    if let Some(size) = env_number::<usize>("L1_CACHE_SIZE").filter(|n| *n > 0) {
+        let ttl = env_number("L1_CACHE_TTL").unwrap_or(5000u64);
+        cacher = cacher.with_l1(cache::L1Cache::new(size, ttl).with_clock(clock.clone()));
+    }
+
+    let zstd_level = std::env::var("CACHE_ZSTD_LEVEL")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| v.parse::<i32>().expect("invalid CACHE_ZSTD_LEVEL"));
+    let encryption_key = std::env::var("CACHE_ENCRYPTION_KEY")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            general_purpose::URL_SAFE_NO_PAD
+                .decode(v.as_bytes())
+                .expect("invalid CACHE_ENCRYPTION_KEY")
+        });
+    let codec = cache::ResponseCodec::new(zstd_level, encryption_key.as_deref())
+        .unwrap_or_else(|err| panic!("CACHE_ENCRYPTION_KEY: {}", err))
+        .with_plaintext_reads(std::env::var("CACHE_ALLOW_PLAINTEXT").unwrap_or_default() == "true")
+        .with_max_size(env_number("CACHE_MAX_RESPONSE_BYTES").unwrap_or(cache::DEFAULT_MAX_SIZE));
+
+    let signer = std::env::var("RESPONSE_SIGNING_KEY")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            let key =
+                parse_signing_key(&v).unwrap_or_else(|err| panic!("RESPONSE_SIGNING_KEY: {}", err));
+            let kid = std::env::var("RESPONSE_SIGNING_KID")
+                .ok()
+                .filter(|v| !v.is_empty());
+            let pk = match key.public_key() {
+                PublicKey::Ed25519(pk) => format!(
+                    "ed25519:{}",
+                    general_purpose::URL_SAFE_NO_PAD.encode(pk.as_bytes())
+                ),
+                PublicKey::Secp256k1(pk) => format!(
+                    "secp256k1:{}",
+                    general_purpose::URL_SAFE_NO_PAD.encode(pk.to_sec1_bytes())
+                ),
+            };
+            log::info!(target: "server", "response signing public key: {}, kid: {:?}", pk, kid);
+            cache::ResponseSigner { key, kid }
+        });
+
+    let agents = split_names(&std::env::var("ALLOW_AGENTS").unwrap_or_default());
+    let admin_agents = split_names(&std::env::var("ADMIN_AGENTS").unwrap_or_default());
 
     let url_vars: HashMap<String, String> = std::env::vars()
         .filter(|(k, _)| k.starts_with("URL_"))
@@ -77,47 +169,102 @@ async fn main() {
         .map(|(k, v)| (k, v.parse().expect("invalid header value")))
         .collect();
 
-    let ecdsa_pub_keys: Vec<ecdsa::VerifyingKey> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("ECDSA_PUB_KEY"))
-        .map(|(_, v)| {
-            let v = general_purpose::URL_SAFE_NO_PAD
-                .decode(v)
-                .expect("invalid base64");
-            ecdsa::VerifyingKey::from_sec1_bytes(&v).expect("invalid ecdsa key")
-        })
-        .collect();
+    let user_agent = std::env::var("PROXY_USER_AGENT")
+        .unwrap_or_else(|_| format!("{}/{}", APP_NAME, APP_VERSION))
+        .parse()
+        .expect("invalid PROXY_USER_AGENT");
+    let mut forward = forward::ForwardPolicy::new(
+        std::env::var("FORWARD_HEADERS")
+            .map(|v| {
+                v.parse()
+                    .unwrap_or_else(|err| panic!("FORWARD_HEADERS: {}", err))
+            })
+            .unwrap_or_default(),
+        user_agent,
+    );
+    for (k, v) in std::env::vars() {
+        if let Some(route) = k.strip_prefix("FORWARD_HEADERS_") {
+            let policy = v.parse().unwrap_or_else(|err| panic!("{}: {}", k, err));
+            forward = forward.with_route(route, policy);
+        }
+    }
 
-    let ed25519_pub_keys: Vec<ed25519_dalek::VerifyingKey> = std::env::vars()
-        .filter(|(k, _)| k.starts_with("ED25519_PUB_KEY"))
-        .map(|(_, v)| {
-            let v = general_purpose::URL_SAFE_NO_PAD
-                .decode(v)
-                .expect("invalid base64");
-            if v.len() != 32 {
-                panic!("invalid eddsa key");
-            }
-            let mut key = [0u8; 32];
-            key.copy_from_slice(&v);
-            ed25519_dalek::VerifyingKey::from_bytes(&key).expect("invalid ecdsa key")
+    let transforms: HashMap<String, cache::JsonTransform> = std::env::vars()
+        .filter(|(k, _)| k.starts_with("TRANSFORM_"))
+        .map(|(k, v)| {
+            let tf =
+                cache::JsonTransform::compile(&v).unwrap_or_else(|err| panic!("{}: {}", k, err));
+            (k, tf)
         })
         .collect();
 
+    let volatile_fields = split_names(&std::env::var("VOLATILE_FIELDS").unwrap_or_default());
+    let secret_params = split_names(&std::env::var("SECRET_QUERY_PARAMS").unwrap_or_default());
+
+    let mut keys = KeyRegistry::default();
+    for (k, v) in std::env::vars() {
+        let key = if k.starts_with("ECDSA_PUB_KEY") {
+            parse_key_info(&v, |v| {
+                ecdsa::VerifyingKey::from_sec1_bytes(v)
+                    .map(PublicKey::Secp256k1)
+                    .map_err(|_| "invalid ecdsa key".to_string())
+            })
+        } else if k.starts_with("ED25519_PUB_KEY") {
+            parse_key_info(&v, |v| {
+                let key: [u8; 32] = v.try_into().map_err(|_| "invalid eddsa key")?;
+                ed25519_dalek::VerifyingKey::from_bytes(&key)
+                    .map(PublicKey::Ed25519)
+                    .map_err(|_| "invalid eddsa key".to_string())
+            })
+        } else {
+            continue;
+        };
+        keys.add(key.unwrap_or_else(|err| panic!("{}: {}", k, err)))
+            .unwrap_or_else(|err| panic!("{}: {}", k, err));
+    }
+
+    let audience = std::env::var("PROXY_AUDIENCE").unwrap_or_default();
+    let ic_root_key = std::env::var("IC_ROOT_KEY").unwrap_or_default();
+    let ic_root_key = if ic_root_key.is_empty() {
+        Vec::new()
+    } else {
+        let key = general_purpose::URL_SAFE_NO_PAD
+            .decode(ic_root_key.as_bytes())
+            .expect("invalid IC_ROOT_KEY");
+        icp::parse_root_key(&key).unwrap_or_else(|err| panic!("IC_ROOT_KEY: {}", err))
+    };
+    // any canister on the IC can sign an ICP token
+    if !ic_root_key.is_empty() && agents.is_empty() {
+        panic!("ALLOW_AGENTS is required with IC_ROOT_KEY");
+    }
+
+    let mut builder = ProxyBuilder::new(cacher)
+        .with_http_client(http_client)
+        .with_codec(codec)
+        .with_agents(agents)
+        .with_admin_agents(admin_agents)
+        .with_volatile_fields(volatile_fields)
+        .with_secret_params(secret_params)
+        .with_forward_policy(forward)
+        .with_keys(keys)
+        .with_audience(&audience)
+        .with_ic_root_key(ic_root_key)
+        .with_clock(clock);
+    if let Some(signer) = signer {
+        builder = builder.with_signer(signer);
+    }
+    for (name, url) in &url_vars {
+        builder = builder.with_url_var(name, url);
+    }
+    for (name, value) in header_vars {
+        builder = builder.with_header_var(&name, value);
+    }
+    for (name, transform) in transforms {
+        builder = builder.with_transform(&name, transform);
+    }
+
     let handle = axum_server::Handle::new();
-    let app = Router::new()
-        .route("/*any", routing::any(handler::proxy))
-        .with_state(handler::AppState {
-            http_client: Arc::new(http_client),
-            cacher: Arc::new(cache::HybridCacher::new(
-                poll_interval,
-                req_timeout,
-                cacher_entry,
-            )),
-            agents: Arc::new(agents),
-            url_vars: Arc::new(url_vars),
-            header_vars: Arc::new(header_vars),
-            ecdsa_pub_keys: Arc::new(ecdsa_pub_keys),
-            ed25519_pub_keys: Arc::new(ed25519_pub_keys),
-        });
+    let app = builder.build();
 
     let addr: SocketAddr = std::env::var("SERVER_ADDR")
         .unwrap_or("127.0.0.1:8080".to_string())
@@ -136,17 +283,140 @@ async fn main() {
                 .unwrap();
         }
         false => {
-            let config = RustlsConfig::from_pem_file(&cert_file, &key_file)
-                .await
-                .unwrap_or_else(|_| panic!("read tls file failed: {}, {}", cert_file, key_file));
-            log::warn!(target: "server", "{}@{} listening on {:?} with tls", APP_NAME, APP_VERSION,addr);
-            axum_server::bind_rustls(addr, config)
-                .handle(handle)
-                .serve(app.into_make_service())
-                .await
-                .unwrap();
+            let client_ca_file = std::env::var("TLS_CLIENT_CA_FILE").unwrap_or_default();
+            if client_ca_file.is_empty() {
+                let config = RustlsConfig::from_pem_file(&cert_file, &key_file)
+                    .await
+                    .unwrap_or_else(|_| {
+                        panic!("read tls file failed: {}, {}", cert_file, key_file)
+                    });
+                log::warn!(target: "server", "{}@{} listening on {:?} with tls", APP_NAME, APP_VERSION,addr);
+                tokio::spawn(shutdown_signal(handle.clone()));
+                axum_server::bind_rustls(addr, config)
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap();
+            } else {
+                let client_auth_required = std::env::var("TLS_CLIENT_AUTH")
+                    .map(|v| v == "required")
+                    .unwrap_or(false);
+                let config = tls::client_auth_config(
+                    &cert_file,
+                    &key_file,
+                    &client_ca_file,
+                    client_auth_required,
+                )
+                .unwrap_or_else(|err| panic!("load tls config failed: {}", err));
+                log::warn!(target: "server", "{}@{} listening on {:?} with mutual tls", APP_NAME, APP_VERSION,addr);
+                tokio::spawn(shutdown_signal(handle.clone()));
+                axum_server::bind(addr)
+                    .acceptor(tls::ClientCertAcceptor::new(RustlsAcceptor::new(config)))
+                    .handle(handle)
+                    .serve(app.into_make_service())
+                    .await
+                    .unwrap();
+            }
+        }
+    }
+
+    if let Some((cacher, path, task)) = memory_snapshot {
+        // no periodic snapshot may replace the final one
+        if let Some(task) = task {
+            task.abort();
+        }
+        match cacher.snapshot(&path) {
+            Ok(n) => log::warn!(target: "server", "saved {} entries to {}", n, path),
+            Err(err) => log::error!(target: "server", "save snapshot failed: {}", err),
+        }
+    }
+    if let Some(cacher) = sled_cacher {
+        cacher
+            .flush()
+            .await
+            .unwrap_or_else(|err| log::error!(target: "server", "flush sled failed: {}", err));
+    }
+}
+
+// Parses a numeric env var, None if not set or empty.
+fn env_number<T: std::str::FromStr>(name: &str) -> Option<T> {
+    std::env::var(name).ok().filter(|v| !v.is_empty()).map(|v| {
+        v.parse()
+            .unwrap_or_else(|_| panic!("invalid {}: {}", name, v))
+    })
+}
+
+// Parses "ed25519:<base64url>" or "secp256k1:<base64url>" private key.
+fn parse_signing_key(spec: &str) -> Result<SigningKey, String> {
+    let (alg, key) = spec
+        .split_once(':')
+        .ok_or("expected ed25519:<key> or secp256k1:<key>")?;
+    let key = general_purpose::URL_SAFE_NO_PAD
+        .decode(key.trim().as_bytes())
+        .map_err(|err| err.to_string())?;
+    match alg.trim() {
+        "ed25519" => {
+            let key: [u8; 32] = key.try_into().map_err(|_| "invalid Ed25519 key")?;
+            Ok(SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                &key,
+            )))
+        }
+        "secp256k1" => ecdsa::SigningKey::from_slice(&key)
+            .map(SigningKey::Secp256k1)
+            .map_err(|_| "invalid Secp256k1 key".to_string()),
+        alg => Err(format!("unsupported key algorithm {}", alg)),
+    }
+}
+
+fn split_names(names: &str) -> BTreeSet<String> {
+    names
+        .split(',')
+        .filter_map(|s| {
+            let s = s.trim();
+            if s.is_empty() {
+                None
+            } else {
+                Some(s.to_string())
+            }
+        })
+        .collect()
+}
+
+// Key format: "<base64 key>[;kid=<id>][;agents=<agent1>,<agent2>][;not_before=<secs>][;not_after=<secs>]"
+fn parse_key_info(
+    spec: &str,
+    parse_key: impl FnOnce(&[u8]) -> Result<PublicKey, String>,
+) -> Result<KeyInfo, String> {
+    let mut parts = spec.split(';').map(|s| s.trim());
+    let key = general_purpose::URL_SAFE_NO_PAD
+        .decode(parts.next().unwrap_or_default())
+        .map_err(|_| "invalid base64".to_string())?;
+    let mut info = KeyInfo::new(parse_key(&key)?);
+    for part in parts.filter(|s| !s.is_empty()) {
+        let (name, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("invalid key option: {}", part))?;
+        let value = value.trim();
+        match name.trim() {
+            "kid" => info.kid = Some(value.to_string()),
+            "agents" => {
+                info.agents = Some(
+                    value
+                        .split(',')
+                        .map(|s| s.trim())
+                        .filter(|s| !s.is_empty())
+                        .map(|s| s.to_string())
+                        .collect(),
+                )
+            }
+            "not_before" => {
+                info.not_before = Some(value.parse().map_err(|_| "invalid not_before")?)
+            }
+            "not_after" => info.not_after = Some(value.parse().map_err(|_| "invalid not_after")?),
+            _ => return Err(format!("unknown key option: {}", name)),
         }
     }
+    Ok(info)
 }
 
 async fn shutdown_signal(handle: axum_server::Handle) {
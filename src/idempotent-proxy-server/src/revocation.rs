@@ -0,0 +1,262 @@
+use base64::{engine::general_purpose, Engine};
+use ciborium::{from_reader, into_writer};
+use idempotent_proxy_types::{
+    auth::{sha3_256, Claims},
+    clock::Clock,
+    err_string,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::cache::{Cacher, HybridCacher};
+
+// Revocations share the cacher keyspace with the idempotency keys of requests,
+// which must not start with this prefix, see `is_reserved_key`.
+const KEY_PREFIX: &str = "_revocation:";
+const TOKEN_KEY_PREFIX: &str = "_revocation:token:";
+const AGENT_KEY_PREFIX: &str = "_revocation:agent:";
+
+/// A token or agent revocation, stored in the cacher backend until `expire_at`
+/// so that all proxy replicas see it.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Revocation {
+    /// Base64url-encoded SHA3-256 hash of the token to revoke, see `token_hash`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_hash: Option<String>,
+    /// Agent whose tokens issued before `not_before` are revoked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub agent: Option<String>,
+    /// UNIX timestamp in seconds, defaults to now. v1 tokens have no issued-at time
+    /// and are always revoked by an agent revocation.
+    #[serde(default)]
+    pub not_before: u64,
+    /// UNIX timestamp in seconds after which the revocation is dropped.
+    /// It should not be earlier than the expiration of the revoked tokens.
+    pub expire_at: u64,
+}
+
+/// Returns true if the cacher key is reserved for revocations.
+pub fn is_reserved_key(key: &str) -> bool {
+    key.starts_with(KEY_PREFIX)
+}
+
+/// Returns the base64url-encoded SHA3-256 hash of the raw (base64-decoded) token.
+pub fn token_hash(token: &[u8]) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(sha3_256(token))
+}
+
+impl Revocation {
+    fn key(&self) -> Result<String, String> {
+        match (&self.token_hash, &self.agent) {
+            (Some(hash), None) if !hash.is_empty() => Ok(format!("{}{}", TOKEN_KEY_PREFIX, hash)),
+            (None, Some(agent)) if !agent.is_empty() => {
+                Ok(format!("{}{}", AGENT_KEY_PREFIX, agent))
+            }
+            _ => Err("exactly one of token_hash and agent is required".to_string()),
+        }
+    }
+
+    pub async fn save(&mut self, cacher: &HybridCacher, clock: &dyn Clock) -> Result<(), String> {
+        let key = self.key()?;
+        let now = clock.now_ms();
+        let expire_at = self
+            .expire_at
+            .checked_mul(1000)
+            .ok_or("expire_at is out of range")?;
+        if expire_at <= now {
+            return Err("expire_at should be in the future".to_string());
+        }
+        if self.agent.is_some() && self.not_before == 0 {
+            self.not_before = now / 1000;
+        }
+
+        let ttl = expire_at - now;
+        let mut data = Vec::new();
+        into_writer(&self, &mut data).map_err(err_string)?;
+        // replace the existing revocation, if any
+        cacher.del(&key).await.map_err(err_string)?;
+        let token = cacher
+            .obtain(&key, ttl)
+            .await
+            .map_err(err_string)?
+            .ok_or("the revocation is being updated concurrently")?;
+        if !cacher
+            .set(&key, token, data, ttl)
+            .await
+            .map_err(err_string)?
+        {
+            return Err("the revocation is being updated concurrently".to_string());
+        }
+        Ok(())
+    }
+
+    pub async fn remove(&self, cacher: &HybridCacher) -> Result<(), String> {
+        cacher.del(&self.key()?).await.map_err(err_string)
+    }
+}
+
+/// Checks the claims of a verified token (with its hash) or a client certificate
+/// against the revocations. Returns the reason if revoked.
+pub async fn check(
+    cacher: &HybridCacher,
+    claims: &Claims,
+    token_hash: Option<&str>,
+) -> Result<Option<String>, String> {
+    if let Some(hash) = token_hash {
+        if cacher
+            .get(&format!("{}{}", TOKEN_KEY_PREFIX, hash))
+            .await
+            .map_err(err_string)?
+            .is_some()
+        {
+            return Ok(Some("token revoked".to_string()));
+        }
+    }
+
+    if let Some(data) = cacher
+        .get(&format!("{}{}", AGENT_KEY_PREFIX, claims.agent))
+        .await
+        .map_err(err_string)?
+    {
+        let revocation: Revocation = from_reader(&data[..]).map_err(err_string)?;
+        if claims.issued_at < revocation.not_before {
+            return Ok(Some(format!(
+                "tokens of agent {} issued before {} are revoked",
+                claims.agent, revocation.not_before
+            )));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cache::MemoryCacher;
+    use idempotent_proxy_types::{
+        clock::{ManualClock, SystemClock},
+        unix_ms,
+    };
+
+    #[tokio::test]
+    async fn test_revocation() {
+        let cacher = HybridCacher::new(10, 1000, Box::new(MemoryCacher::default()));
+        let now = unix_ms() / 1000;
+        let hash = token_hash(b"token");
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now - 10,
+            agent: "alice".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(check(&cacher, &claims, Some(&hash)).await.unwrap(), None);
+
+        let mut r = Revocation {
+            token_hash: Some(hash.clone()),
+            expire_at: now + 3600,
+            ..Default::default()
+        };
+        r.save(&cacher, &SystemClock).await.unwrap();
+        assert!(check(&cacher, &claims, Some(&hash))
+            .await
+            .unwrap()
+            .is_some());
+        assert_eq!(
+            check(&cacher, &claims, Some(&token_hash(b"other")))
+                .await
+                .unwrap(),
+            None
+        );
+        r.remove(&cacher).await.unwrap();
+        assert_eq!(check(&cacher, &claims, Some(&hash)).await.unwrap(), None);
+
+        let mut r = Revocation {
+            agent: Some("alice".to_string()),
+            not_before: now - 5,
+            expire_at: now + 3600,
+            ..Default::default()
+        };
+        r.save(&cacher, &SystemClock).await.unwrap();
+        assert!(check(&cacher, &claims, None).await.unwrap().is_some());
+        let fresh = Claims {
+            issued_at: now,
+            ..claims.clone()
+        };
+        assert_eq!(check(&cacher, &fresh, None).await.unwrap(), None);
+        // overwrite the existing revocation
+        r.not_before = now + 1;
+        r.save(&cacher, &SystemClock).await.unwrap();
+        assert!(check(&cacher, &fresh, None).await.unwrap().is_some());
+
+        assert!(Revocation {
+            expire_at: now + 3600,
+            ..Default::default()
+        }
+        .save(&cacher, &SystemClock)
+        .await
+        .is_err());
+        assert!(Revocation {
+            agent: Some("alice".to_string()),
+            expire_at: now - 1,
+            ..Default::default()
+        }
+        .save(&cacher, &SystemClock)
+        .await
+        .is_err());
+
+        // expire_at must be after the clock's time, not_before defaults to it
+        let clock = ManualClock::new(now * 1000);
+        let mut r = Revocation {
+            agent: Some("bob".to_string()),
+            expire_at: now,
+            ..Default::default()
+        };
+        assert!(r.save(&cacher, &clock).await.is_err());
+        r.expire_at = u64::MAX;
+        assert_eq!(
+            r.save(&cacher, &clock).await.unwrap_err(),
+            "expire_at is out of range"
+        );
+        r.expire_at = now;
+        clock.set(now * 1000 - 1);
+        r.save(&cacher, &clock).await.unwrap();
+        assert_eq!(r.not_before, now - 1);
+
+        assert!(is_reserved_key(&r.key().unwrap()));
+        assert!(is_reserved_key("_revocation:token:key001"));
+        assert!(!is_reserved_key("alice:GET:key001"));
+    }
+
+    #[tokio::test]
+    async fn test_revocation_not_evicted() {
+        let cacher = HybridCacher::new(10, 1000, Box::new(MemoryCacher::with_shards(1, 10, 4096)));
+        let now = unix_ms() / 1000;
+        let hash = token_hash(b"token");
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now - 10,
+            agent: "alice".to_string(),
+            ..Default::default()
+        };
+        Revocation {
+            token_hash: Some(hash.clone()),
+            expire_at: now + 3600,
+            ..Default::default()
+        }
+        .save(&cacher, &SystemClock)
+        .await
+        .unwrap();
+
+        // a burst of requests fills the cacher and evicts every other completed entry
+        for i in 0..100 {
+            let key = format!("alice:GET:key{:03}", i);
+            let token = cacher.obtain(&key, 1000).await.unwrap().unwrap();
+            assert!(cacher.set(&key, token, vec![1; 100], 1000).await.unwrap());
+        }
+        assert_eq!(cacher.get("alice:GET:key000").await.unwrap(), None);
+        assert_eq!(
+            check(&cacher, &claims, Some(&hash)).await.unwrap(),
+            Some("token revoked".to_string())
+        );
+    }
+}
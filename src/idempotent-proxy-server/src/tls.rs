@@ -0,0 +1,169 @@
+use axum::{middleware::AddExtension, Extension};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+};
+use futures::future::BoxFuture;
+use idempotent_proxy_types::err_string;
+use rustls::{
+    crypto::ring::default_provider, pki_types::CertificateDer, server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+use std::{fs::File, io, io::BufReader, sync::Arc};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use x509_parser::prelude::*;
+
+/// Identity of a client authenticated by its TLS certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity {
+    pub agent: String,
+}
+
+impl ClientIdentity {
+    /// Takes the agent name from the first DNS, URI or email SAN of the certificate,
+    /// or from the subject common name if there is no such SAN.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, cert) = X509Certificate::from_der(der).ok()?;
+        if let Ok(Some(san)) = cert.subject_alternative_name() {
+            for name in san.value.general_names.iter() {
+                match name {
+                    GeneralName::DNSName(v) | GeneralName::URI(v) | GeneralName::RFC822Name(v) => {
+                        return Some(Self {
+                            agent: v.to_string(),
+                        });
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let cn = cert.subject().iter_common_name().next()?;
+        cn.as_str().ok().map(|cn| Self {
+            agent: cn.to_string(),
+        })
+    }
+}
+
+/// Builds a TLS config that verifies client certificates against the CA bundle in `client_ca_file`.
+/// If `client_auth_required` is false, clients without a certificate are accepted too
+/// and must authenticate with a proxy token.
+pub fn client_auth_config(
+    cert_file: &str,
+    key_file: &str,
+    client_ca_file: &str,
+    client_auth_required: bool,
+) -> Result<RustlsConfig, String> {
+    let provider = Arc::new(default_provider());
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(client_ca_file)? {
+        roots.add(cert).map_err(err_string)?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider.clone());
+    let verifier = if client_auth_required {
+        verifier
+    } else {
+        verifier.allow_unauthenticated()
+    }
+    .build()
+    .map_err(err_string)?;
+
+    let key = rustls_pemfile::private_key(&mut BufReader::new(
+        File::open(key_file).map_err(err_string)?,
+    ))
+    .map_err(err_string)?
+    .ok_or_else(|| format!("no private key in {}", key_file))?;
+    let mut config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(err_string)?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(read_certs(cert_file)?, key)
+        .map_err(err_string)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+fn read_certs(file: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let mut reader = BufReader::new(File::open(file).map_err(err_string)?);
+    rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(err_string)
+}
+
+/// A rustls acceptor that adds the `Option<ClientIdentity>` of the peer certificate
+/// to the request extensions.
+#[derive(Debug, Clone)]
+pub struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl ClientCertAcceptor {
+    pub fn new(inner: RustlsAcceptor) -> Self {
+        Self { inner }
+    }
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, Option<ClientIdentity>>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| ClientIdentity::from_der(cert));
+            Ok((stream, Extension(identity).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{CertificateParams, DistinguishedName, DnType, KeyPair, SanType};
+
+    #[test]
+    fn test_client_identity() {
+        let key = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["agent1.internal".to_string()]).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "agent-cn");
+        let cert = params.clone().self_signed(&key).unwrap();
+        assert_eq!(
+            ClientIdentity::from_der(cert.der()).unwrap().agent,
+            "agent1.internal"
+        );
+
+        params.subject_alt_names = vec![SanType::URI(
+            "spiffe://example.org/agent2".try_into().unwrap(),
+        )];
+        let cert = params.clone().self_signed(&key).unwrap();
+        assert_eq!(
+            ClientIdentity::from_der(cert.der()).unwrap().agent,
+            "spiffe://example.org/agent2"
+        );
+
+        params.subject_alt_names = vec![];
+        let cert = params.clone().self_signed(&key).unwrap();
+        assert_eq!(
+            ClientIdentity::from_der(cert.der()).unwrap().agent,
+            "agent-cn"
+        );
+
+        assert!(ClientIdentity::from_der(b"not a certificate").is_none());
+    }
+}
@@ -7,8 +7,12 @@ use k256::{
 use serde::{Deserialize, Serialize};
 use serde_bytes::ByteBuf;
 use sha3::{Digest, Sha3_256};
+use std::collections::{BTreeSet, HashMap};
 
-use crate::unix_ms;
+use crate::{
+    clock::{Clock, SystemClock},
+    unix_ms,
+};
 
 const PERMITTED_DRIFT: u64 = 10; // seconds
 
@@ -16,6 +20,126 @@ const PERMITTED_DRIFT: u64 = 10; // seconds
 #[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
 pub struct Token(pub u64, pub String, pub ByteBuf);
 
+pub const TOKEN_V2: u64 = 2;
+
+// Token v2 format: [2, claims, signature]
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct TokenV2(pub u64, pub Claims, pub ByteBuf);
+
+/// Claims carried by a v2 token. A v1 token is mapped to claims with an empty
+/// audience, `issued_at` of 0 and no scope restrictions.
+#[derive(Debug, Default, Deserialize, Serialize, PartialEq, Eq, Clone)]
+pub struct Claims {
+    /// Expiration time, UNIX timestamp in seconds.
+    #[serde(rename = "exp")]
+    pub expire_at: u64,
+    /// Issued-at time, UNIX timestamp in seconds.
+    #[serde(rename = "iat")]
+    pub issued_at: u64,
+    /// Agent name.
+    #[serde(rename = "sub")]
+    pub agent: String,
+    /// Identifier of the proxy deployment the token is issued for.
+    #[serde(rename = "aud")]
+    pub audience: String,
+    /// Hosts (x-forwarded-host) the token may access, `None` means any host.
+    #[serde(rename = "hosts", default, skip_serializing_if = "Option::is_none")]
+    pub hosts: Option<Vec<String>>,
+    /// URL vars (URL_*) the token may access, `None` means any URL var.
+    #[serde(rename = "vars", default, skip_serializing_if = "Option::is_none")]
+    pub url_vars: Option<Vec<String>>,
+    /// ID of the key that signed the token, used for key lookup in `KeyRegistry`.
+    #[serde(rename = "kid", default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+}
+
+impl Claims {
+    /// Returns the CBOR-encoded message to sign: [2, claims].
+    pub fn to_message(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(&(TOKEN_V2, self), &mut buf).expect("failed to encode data in CBOR format");
+        buf
+    }
+
+    /// Returns the CBOR-encoded token: [2, claims, signature].
+    pub fn to_token(&self, sig: &[u8]) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(&(TOKEN_V2, self, ByteBuf::from(sig)), &mut buf)
+            .expect("failed to encode in CBOR format");
+        buf
+    }
+
+    /// Checks `expire_at` and `issued_at` against `now` in seconds.
+    pub fn check_time(&self, now: u64) -> Result<(), String> {
+        if self.expire_at + PERMITTED_DRIFT < now {
+            return Err("token expired".to_string());
+        }
+        if self.issued_at > now + PERMITTED_DRIFT {
+            return Err("token issued in the future".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        match &self.hosts {
+            None => true,
+            Some(hosts) => hosts.iter().any(|h| h.eq_ignore_ascii_case(host)),
+        }
+    }
+
+    pub fn allows_url_var(&self, name: &str) -> bool {
+        match &self.url_vars {
+            None => true,
+            Some(vars) => vars.iter().any(|v| v == name),
+        }
+    }
+}
+
+// Decodes a v1 or v2 token, checks its time claims and returns the claims,
+// the signed message and the signature.
+fn decode_token(data: &[u8], clock: &dyn Clock) -> Result<(Claims, Vec<u8>, ByteBuf), String> {
+    let (claims, msg, sig) = match from_reader::<TokenV2, _>(data) {
+        Ok(TokenV2(TOKEN_V2, claims, sig)) => {
+            let msg = v2_message(data).ok_or("invalid token v2 format")?;
+            (claims, msg, sig)
+        }
+        Ok(TokenV2(v, _, _)) => return Err(format!("unsupported token version {}", v)),
+        Err(_) => {
+            let token: Token = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+            let mut msg: Vec<u8> = Vec::new();
+            into_writer(&(token.0, &token.1), &mut msg)
+                .expect("failed to encode data in CBOR format");
+            let claims = Claims {
+                expire_at: token.0,
+                agent: token.1,
+                ..Default::default()
+            };
+            (claims, msg, token.2)
+        }
+    };
+
+    claims.check_time(clock.now_ms() / 1000)?;
+    Ok((claims, msg, sig))
+}
+
+// Returns the signed message [2, claims] of a v2 token [2, claims, signature]
+// from the received bytes, so that the signature is not checked against re-encoded
+// claims, which may differ from what the issuer encoded.
+fn v2_message(data: &[u8]) -> Option<Vec<u8>> {
+    // a definite-length array of 3 items
+    if data.first() != Some(&0x83) {
+        return None;
+    }
+    let mut rest = &data[1..];
+    let _: u64 = from_reader(&mut rest).ok()?;
+    let _: ciborium::Value = from_reader(&mut rest).ok()?;
+    let end = data.len() - rest.len();
+    let mut msg = Vec::with_capacity(end);
+    msg.push(0x82); // a definite-length array of 2 items
+    msg.extend_from_slice(&data[1..end]);
+    Some(msg)
+}
+
 pub fn ed25519_sign(key: &ed25519_dalek::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
     into_writer(&(expire_at, &agent), &mut buf).expect("failed to encode data in CBOR format");
@@ -45,6 +169,36 @@ pub fn ed25519_verify(keys: &[ed25519_dalek::VerifyingKey], data: &[u8]) -> Resu
     Err("failed to verify Ed25519 signature".to_string())
 }
 
+pub fn ed25519_sign_claims(key: &ed25519_dalek::SigningKey, claims: &Claims) -> Vec<u8> {
+    let sig = key.sign(&claims.to_message()).to_bytes();
+    claims.to_token(&sig)
+}
+
+/// Verifies a v1 or v2 token signed by one of the Ed25519 keys.
+pub fn ed25519_verify_claims(
+    keys: &[ed25519_dalek::VerifyingKey],
+    data: &[u8],
+) -> Result<Claims, String> {
+    ed25519_verify_claims_with_clock(keys, data, &SystemClock)
+}
+
+pub fn ed25519_verify_claims_with_clock(
+    keys: &[ed25519_dalek::VerifyingKey],
+    data: &[u8],
+    clock: &dyn Clock,
+) -> Result<Claims, String> {
+    let (claims, msg, sig) = decode_token(data, clock)?;
+    let sig = ed25519_dalek::Signature::from_slice(sig.as_slice())
+        .map_err(|_err| "failed to parse Ed25519 signature")?;
+    for key in keys.iter() {
+        if key.verify_strict(&msg, &sig).is_ok() {
+            return Ok(claims);
+        }
+    }
+
+    Err("failed to verify Ed25519 signature".to_string())
+}
+
 // Secp256k1
 pub fn ecdsa_sign(key: &ecdsa::SigningKey, expire_at: u64, agent: String) -> Vec<u8> {
     let mut buf: Vec<u8> = Vec::new();
@@ -80,6 +234,197 @@ pub fn ecdsa_verify(keys: &[ecdsa::VerifyingKey], data: &[u8]) -> Result<Token,
     Err("failed to verify ECDSA/Secp256k1 signature".to_string())
 }
 
+// Secp256k1
+pub fn ecdsa_sign_claims(key: &ecdsa::SigningKey, claims: &Claims) -> Vec<u8> {
+    let digest = sha3_256(&claims.to_message());
+    let sig: ecdsa::Signature = key
+        .sign_prehash(&digest)
+        .expect("failed to sign Secp256k1 signature");
+    claims.to_token(&sig.to_vec())
+}
+
+// Secp256k1, verifies a v1 or v2 token signed by one of the keys.
+pub fn ecdsa_verify_claims(keys: &[ecdsa::VerifyingKey], data: &[u8]) -> Result<Claims, String> {
+    ecdsa_verify_claims_with_clock(keys, data, &SystemClock)
+}
+
+// Secp256k1, verifies a v1 or v2 token signed by one of the keys at the clock's time.
+pub fn ecdsa_verify_claims_with_clock(
+    keys: &[ecdsa::VerifyingKey],
+    data: &[u8],
+    clock: &dyn Clock,
+) -> Result<Claims, String> {
+    let (claims, msg, sig) = decode_token(data, clock)?;
+    let sig = ecdsa::Signature::try_from(sig.as_slice())
+        .map_err(|_err| "failed to parse Secp256k1 signature")?;
+    let digest = sha3_256(&msg);
+
+    for key in keys.iter() {
+        if key.verify_prehash(digest.as_slice(), &sig).is_ok() {
+            return Ok(claims);
+        }
+    }
+
+    Err("failed to verify ECDSA/Secp256k1 signature".to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublicKey {
+    Ed25519(ed25519_dalek::VerifyingKey),
+    Secp256k1(ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    pub fn verify(&self, msg: &[u8], sig: &[u8]) -> Result<(), String> {
+        match self {
+            PublicKey::Ed25519(key) => {
+                let sig = ed25519_dalek::Signature::from_slice(sig)
+                    .map_err(|_err| "failed to parse Ed25519 signature")?;
+                key.verify_strict(msg, &sig)
+                    .map_err(|_err| "failed to verify Ed25519 signature".to_string())
+            }
+            PublicKey::Secp256k1(key) => {
+                let sig = ecdsa::Signature::try_from(sig)
+                    .map_err(|_err| "failed to parse Secp256k1 signature")?;
+                key.verify_prehash(&sha3_256(msg), &sig)
+                    .map_err(|_err| "failed to verify ECDSA/Secp256k1 signature".to_string())
+            }
+        }
+    }
+}
+
+/// A private key to sign proxy responses, the counterpart of `PublicKey`.
+#[derive(Debug, Clone)]
+pub enum SigningKey {
+    Ed25519(ed25519_dalek::SigningKey),
+    Secp256k1(ecdsa::SigningKey),
+}
+
+impl SigningKey {
+    pub fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            SigningKey::Ed25519(key) => key.sign(msg).to_bytes().to_vec(),
+            SigningKey::Secp256k1(key) => {
+                let sig: ecdsa::Signature = key
+                    .sign_prehash(&sha3_256(msg))
+                    .expect("failed to sign Secp256k1 signature");
+                sig.to_vec()
+            }
+        }
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        match self {
+            SigningKey::Ed25519(key) => PublicKey::Ed25519(key.verifying_key()),
+            SigningKey::Secp256k1(key) => PublicKey::Secp256k1(*key.verifying_key()),
+        }
+    }
+}
+
+/// A public key in `KeyRegistry` with its restrictions.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub kid: Option<String>,
+    pub key: PublicKey,
+    /// The key is not valid before this UNIX timestamp in seconds.
+    pub not_before: Option<u64>,
+    /// The key is not valid after this UNIX timestamp in seconds.
+    pub not_after: Option<u64>,
+    /// Agents the key may sign tokens for, `None` means any agent.
+    pub agents: Option<BTreeSet<String>>,
+}
+
+impl KeyInfo {
+    pub fn new(key: PublicKey) -> Self {
+        Self {
+            kid: None,
+            key,
+            not_before: None,
+            not_after: None,
+            agents: None,
+        }
+    }
+
+    fn check(&self, claims: &Claims, now: u64) -> Result<(), String> {
+        if self.not_before.is_some_and(|t| now + PERMITTED_DRIFT < t) {
+            return Err("key is not yet valid".to_string());
+        }
+        if self.not_after.is_some_and(|t| t + PERMITTED_DRIFT < now) {
+            return Err("key expired".to_string());
+        }
+        if let Some(agents) = &self.agents {
+            if !agents.contains(&claims.agent) {
+                return Err(format!(
+                    "key is not allowed to sign for agent {}",
+                    claims.agent
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Ed25519 and Secp256k1 keys for token verification.
+/// Tokens with a `kid` are verified by that key only, others by any matching key.
+#[derive(Debug, Clone, Default)]
+pub struct KeyRegistry {
+    keys: Vec<KeyInfo>,
+    kids: HashMap<String, usize>,
+}
+
+impl KeyRegistry {
+    pub fn add(&mut self, key: KeyInfo) -> Result<(), String> {
+        if let Some(kid) = &key.kid {
+            if self.kids.contains_key(kid) {
+                return Err(format!("duplicate key id {}", kid));
+            }
+            self.kids.insert(kid.clone(), self.keys.len());
+        }
+        self.keys.push(key);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn verify(&self, data: &[u8]) -> Result<Claims, String> {
+        self.verify_with_clock(data, &SystemClock)
+    }
+
+    /// Verifies a token, checking the token and key validity times at the clock's time.
+    pub fn verify_with_clock(&self, data: &[u8], clock: &dyn Clock) -> Result<Claims, String> {
+        let (claims, msg, sig) = decode_token(data, clock)?;
+        let now = clock.now_ms() / 1000;
+        if let Some(kid) = &claims.kid {
+            let key = self
+                .kids
+                .get(kid)
+                .map(|i| &self.keys[*i])
+                .ok_or_else(|| format!("unknown key id {}", kid))?;
+            key.check(&claims, now)?;
+            key.key.verify(&msg, &sig)?;
+            return Ok(claims);
+        }
+
+        let mut last_err = "no key to verify the token".to_string();
+        for key in self.keys.iter() {
+            match key
+                .check(&claims, now)
+                .and_then(|_| key.key.verify(&msg, &sig))
+            {
+                Ok(_) => return Ok(claims),
+                Err(err) => last_err = err,
+            }
+        }
+        Err(last_err)
+    }
+}
+
 pub fn sha3_256(data: &[u8]) -> [u8; 32] {
     let mut hasher = Sha3_256::new();
     hasher.update(data);
@@ -89,6 +434,7 @@ pub fn sha3_256(data: &[u8]) -> [u8; 32] {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::clock::ManualClock;
     use base64::{engine::general_purpose, Engine};
     use k256::PublicKey;
     use rand_core::{OsRng, RngCore};
@@ -107,6 +453,271 @@ mod test {
         assert_eq!(token.1, agent);
     }
 
+    #[test]
+    fn test_claims_token() {
+        let mut secret_key = [0u8; 32];
+        OsRng.fill_bytes(&mut secret_key);
+        let ed_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
+        let ec_key = ecdsa::SigningKey::random(&mut OsRng);
+        let now = unix_ms() / 1000;
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now,
+            agent: "alice".to_string(),
+            audience: "proxy-1".to_string(),
+            hosts: Some(vec!["httpbin.org".to_string()]),
+            url_vars: Some(vec![]),
+            kid: None,
+        };
+
+        let signed = ed25519_sign_claims(&ed_key, &claims);
+        assert_eq!(
+            ed25519_verify_claims(&[ed_key.verifying_key()], &signed).unwrap(),
+            claims
+        );
+        assert!(ed25519_verify(&[ed_key.verifying_key()], &signed).is_err());
+
+        let signed = ecdsa_sign_claims(&ec_key, &claims);
+        let got = ecdsa_verify_claims(&[ecdsa::VerifyingKey::from(&ec_key)], &signed).unwrap();
+        assert_eq!(got, claims);
+        assert!(got.allows_host("HTTPBIN.org"));
+        assert!(!got.allows_host("example.com"));
+        assert!(!got.allows_url_var("URL_HTTPBIN"));
+
+        // v1 tokens are accepted with empty audience and no scope
+        let signed = ed25519_sign(&ed_key, now + 3600, "bob".to_string());
+        let got = ed25519_verify_claims(&[ed_key.verifying_key()], &signed).unwrap();
+        assert_eq!(got.agent, "bob");
+        assert_eq!(got.audience, "");
+        assert!(got.allows_host("example.com"));
+        assert!(got.allows_url_var("URL_HTTPBIN"));
+
+        let mut tampered = claims.clone();
+        tampered.audience = "proxy-2".to_string();
+        let sig = ed25519_verify_claims(&[ed_key.verifying_key()], &tampered.to_token(&[0u8; 64]));
+        assert!(sig.is_err());
+
+        let expired = Claims {
+            expire_at: now - PERMITTED_DRIFT - 1,
+            ..claims.clone()
+        };
+        let signed = ed25519_sign_claims(&ed_key, &expired);
+        assert_eq!(
+            ed25519_verify_claims(&[ed_key.verifying_key()], &signed).unwrap_err(),
+            "token expired"
+        );
+
+        let future = Claims {
+            issued_at: now + PERMITTED_DRIFT + 60,
+            ..claims
+        };
+        let signed = ed25519_sign_claims(&ed_key, &future);
+        assert!(ed25519_verify_claims(&[ed_key.verifying_key()], &signed).is_err());
+    }
+
+    #[test]
+    fn test_token_time_with_clock() {
+        use super::PublicKey;
+
+        let ed_key = ed25519_dalek::SigningKey::from_bytes(&[1u8; 32]);
+        let ec_key = ecdsa::SigningKey::from_slice(&[2u8; 32]).unwrap();
+        let ed_keys = [ed_key.verifying_key()];
+        let ec_keys = [ecdsa::VerifyingKey::from(&ec_key)];
+        let now = 1_700_000_000;
+        let clock = ManualClock::new(now * 1000);
+
+        // v1 tokens are accepted up to PERMITTED_DRIFT after expiration
+        let signed = ed25519_sign(&ed_key, now, "alice".to_string());
+        let ec_signed = ecdsa_sign(&ec_key, now, "alice".to_string());
+        clock.set((now + PERMITTED_DRIFT) * 1000 + 999);
+        assert!(ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).is_ok());
+        assert!(ecdsa_verify_claims_with_clock(&ec_keys, &ec_signed, &clock).is_ok());
+        clock.advance(1);
+        assert_eq!(
+            ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).unwrap_err(),
+            "token expired"
+        );
+        assert!(ecdsa_verify_claims_with_clock(&ec_keys, &ec_signed, &clock).is_err());
+        // tokens verified by the system clock are long expired
+        assert!(ed25519_verify(&ed_keys, &signed).is_err());
+
+        let claims = Claims {
+            expire_at: now,
+            issued_at: now - 60,
+            agent: "alice".to_string(),
+            ..Default::default()
+        };
+        let signed = ed25519_sign_claims(&ed_key, &claims);
+        let ec_signed = ecdsa_sign_claims(&ec_key, &claims);
+        clock.set((now + PERMITTED_DRIFT) * 1000);
+        assert_eq!(
+            ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).unwrap(),
+            claims
+        );
+        assert!(ecdsa_verify_claims_with_clock(&ec_keys, &ec_signed, &clock).is_ok());
+        clock.set((now + PERMITTED_DRIFT + 1) * 1000);
+        assert_eq!(
+            ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).unwrap_err(),
+            "token expired"
+        );
+        assert!(ecdsa_verify_claims_with_clock(&ec_keys, &ec_signed, &clock).is_err());
+
+        // tokens issued up to PERMITTED_DRIFT in the future are accepted
+        clock.set((now - 60 - PERMITTED_DRIFT) * 1000);
+        assert!(ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).is_ok());
+        clock.set((now - 60 - PERMITTED_DRIFT - 1) * 1000);
+        assert_eq!(
+            ed25519_verify_claims_with_clock(&ed_keys, &signed, &clock).unwrap_err(),
+            "token issued in the future"
+        );
+
+        // key validity times
+        let mut registry = KeyRegistry::default();
+        registry
+            .add(KeyInfo {
+                not_before: Some(now - 30),
+                not_after: Some(now - 10),
+                ..KeyInfo::new(PublicKey::Ed25519(ed_key.verifying_key()))
+            })
+            .unwrap();
+        clock.set((now - 30 - PERMITTED_DRIFT) * 1000);
+        assert!(registry.verify_with_clock(&signed, &clock).is_ok());
+        clock.set((now - 30 - PERMITTED_DRIFT - 1) * 1000);
+        assert_eq!(
+            registry.verify_with_clock(&signed, &clock).unwrap_err(),
+            "key is not yet valid"
+        );
+        clock.set((now - 10 + PERMITTED_DRIFT) * 1000);
+        assert!(registry.verify_with_clock(&signed, &clock).is_ok());
+        clock.set((now - 10 + PERMITTED_DRIFT + 1) * 1000);
+        assert_eq!(
+            registry.verify_with_clock(&signed, &clock).unwrap_err(),
+            "key expired"
+        );
+    }
+
+    #[test]
+    fn test_claims_token_encoding() {
+        // claims encoded by another issuer, with a different key order
+        #[derive(Serialize)]
+        struct IssuerClaims {
+            aud: String,
+            sub: String,
+            exp: u64,
+            iat: u64,
+        }
+
+        let ed_key = ed25519_dalek::SigningKey::from_bytes(&[3u8; 32]);
+        let now = unix_ms() / 1000;
+        let issued = IssuerClaims {
+            aud: "proxy-1".to_string(),
+            sub: "alice".to_string(),
+            exp: now + 3600,
+            iat: now,
+        };
+        let mut msg = Vec::new();
+        into_writer(&(TOKEN_V2, &issued), &mut msg).unwrap();
+        let sig = ed_key.sign(&msg).to_bytes();
+        let mut token = Vec::new();
+        into_writer(&(TOKEN_V2, &issued, ByteBuf::from(sig)), &mut token).unwrap();
+
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now,
+            agent: "alice".to_string(),
+            audience: "proxy-1".to_string(),
+            ..Default::default()
+        };
+        assert_ne!(claims.to_message(), msg);
+        assert_eq!(
+            ed25519_verify_claims(&[ed_key.verifying_key()], &token).unwrap(),
+            claims
+        );
+
+        // the signature covers the received claims
+        let mut tampered = token.clone();
+        let i = tampered.windows(5).position(|w| w == b"alice").unwrap();
+        tampered[i] = b'b'; // "alice" -> "blice"
+        assert_eq!(
+            ed25519_verify_claims(&[ed_key.verifying_key()], &tampered).unwrap_err(),
+            "failed to verify Ed25519 signature"
+        );
+    }
+
+    #[test]
+    fn test_key_registry() {
+        use super::PublicKey;
+
+        let mut secret_key = [0u8; 32];
+        OsRng.fill_bytes(&mut secret_key);
+        let ed_key = ed25519_dalek::SigningKey::from_bytes(&secret_key);
+        let ec_key = ecdsa::SigningKey::random(&mut OsRng);
+        let now = unix_ms() / 1000;
+
+        let mut registry = KeyRegistry::default();
+        registry
+            .add(KeyInfo::new(PublicKey::Ed25519(ed_key.verifying_key())))
+            .unwrap();
+        registry
+            .add(KeyInfo {
+                kid: Some("team-a".to_string()),
+                agents: Some(BTreeSet::from(["alice".to_string()])),
+                ..KeyInfo::new(PublicKey::Secp256k1(ecdsa::VerifyingKey::from(&ec_key)))
+            })
+            .unwrap();
+        assert!(registry
+            .add(KeyInfo {
+                kid: Some("team-a".to_string()),
+                ..KeyInfo::new(PublicKey::Ed25519(ed_key.verifying_key()))
+            })
+            .is_err());
+        assert_eq!(registry.len(), 2);
+
+        // both algorithms are accepted at the same time
+        let signed = ed25519_sign(&ed_key, now + 3600, "bob".to_string());
+        assert_eq!(registry.verify(&signed).unwrap().agent, "bob");
+        let signed = ecdsa_sign(&ec_key, now + 3600, "alice".to_string());
+        assert_eq!(registry.verify(&signed).unwrap().agent, "alice");
+        // team-a key may only sign for alice
+        let signed = ecdsa_sign(&ec_key, now + 3600, "bob".to_string());
+        assert!(registry.verify(&signed).is_err());
+
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now,
+            agent: "alice".to_string(),
+            kid: Some("team-a".to_string()),
+            ..Default::default()
+        };
+        let signed = ecdsa_sign_claims(&ec_key, &claims);
+        assert_eq!(registry.verify(&signed).unwrap(), claims);
+        // kid points to the ECDSA key, so an Ed25519 signature fails
+        let signed = ed25519_sign_claims(&ed_key, &claims);
+        assert!(registry.verify(&signed).is_err());
+        let signed = ecdsa_sign_claims(
+            &ec_key,
+            &Claims {
+                kid: Some("team-b".to_string()),
+                ..claims.clone()
+            },
+        );
+        assert_eq!(
+            registry.verify(&signed).unwrap_err(),
+            "unknown key id team-b"
+        );
+
+        let mut registry = KeyRegistry::default();
+        registry
+            .add(KeyInfo {
+                kid: Some("team-a".to_string()),
+                not_after: Some(now - PERMITTED_DRIFT - 1),
+                ..KeyInfo::new(PublicKey::Secp256k1(ecdsa::VerifyingKey::from(&ec_key)))
+            })
+            .unwrap();
+        let signed = ecdsa_sign_claims(&ec_key, &claims);
+        assert_eq!(registry.verify(&signed).unwrap_err(), "key expired");
+    }
+
     #[test]
     #[ignore]
     fn test_secp256k1_token() {
@@ -0,0 +1,64 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use crate::unix_ms;
+
+/// A source of the current time, so that expirations can be tested without sleeping.
+pub trait Clock: Send + Sync {
+    /// Returns the current unix timestamp in milliseconds.
+    fn now_ms(&self) -> u64;
+}
+
+/// The system clock.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        unix_ms()
+    }
+}
+
+/// A clock that only moves when it is told to. Clones share the same time.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<AtomicU64>);
+
+impl ManualClock {
+    pub fn new(now_ms: u64) -> Self {
+        Self(Arc::new(AtomicU64::new(now_ms)))
+    }
+
+    pub fn set(&self, now_ms: u64) {
+        self.0.store(now_ms, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock() {
+        let now = SystemClock.now_ms();
+        assert!(now > 0 && now <= unix_ms());
+
+        let clock = ManualClock::new(1000);
+        let shared: Arc<dyn Clock> = Arc::new(clock.clone());
+        clock.advance(500);
+        assert_eq!(shared.now_ms(), 1500);
+        clock.set(100);
+        assert_eq!(shared.now_ms(), 100);
+    }
+}
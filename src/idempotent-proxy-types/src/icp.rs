@@ -0,0 +1,406 @@
+use ciborium::{from_reader, into_writer};
+use ic_canister_sig_creation::{
+    delegation_signature_msg, extract_raw_root_pk_from_der, CanisterSigPublicKey,
+    DELEGATION_SIG_DOMAIN, IC_ROOT_PK_LENGTH,
+};
+use ic_signature_verification::verify_canister_sig;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::{
+    auth::{Claims, TOKEN_V2},
+    clock::{Clock, SystemClock},
+};
+
+/// Domain separator of the claims signed by a canister or by the last delegated key.
+pub const PROXY_TOKEN_SIG_DOMAIN: &[u8] = b"idempotent-proxy-token";
+
+// Same limit as the IC for request delegation chains.
+const MAX_DELEGATIONS: usize = 20;
+
+const ED25519_DER_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// A delegation as defined by the IC interface specification.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Delegation {
+    /// DER-encoded Ed25519 public key of the delegate.
+    pub pubkey: ByteBuf,
+    /// Expiration time, UNIX timestamp in nanoseconds.
+    pub expiration: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub targets: Option<Vec<ByteBuf>>,
+}
+
+impl Delegation {
+    /// Returns the domain-separated message signed by the delegator.
+    pub fn to_message(&self) -> Vec<u8> {
+        let targets = self
+            .targets
+            .as_ref()
+            .map(|ts| ts.iter().map(|t| t.to_vec()).collect::<Vec<_>>());
+        signing_message(
+            DELEGATION_SIG_DOMAIN,
+            &delegation_signature_msg(&self.pubkey, self.expiration, targets.as_ref()),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct SignedDelegation {
+    pub delegation: Delegation,
+    pub signature: ByteBuf,
+}
+
+/// A proxy token signed with an ICP canister signature, directly or through
+/// a chain of Ed25519 delegations rooted in the canister signature public key.
+/// The agent is the text form of the canister principal, not the `sub` claim.
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IcpToken {
+    /// The CBOR-encoded claims message [2, claims] as signed, see `Claims::to_message`.
+    /// The signature is verified over these bytes, not over re-encoded claims.
+    pub claims: ByteBuf,
+    /// DER-encoded canister signature public key.
+    pub public_key: ByteBuf,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delegations: Vec<SignedDelegation>,
+    /// Signature of `claims_message(&claims)` by the canister or the last delegated key.
+    pub signature: ByteBuf,
+}
+
+/// Returns the domain-separated message signed by an `IcpToken` from its claims bytes.
+pub fn claims_message(claims: &[u8]) -> Vec<u8> {
+    signing_message(PROXY_TOKEN_SIG_DOMAIN, claims)
+}
+
+// The message is prefixed with the domain length and the domain, see
+// https://internetcomputer.org/docs/current/references/ic-interface-spec#signatures
+fn signing_message(domain: &[u8], msg: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(1 + domain.len() + msg.len());
+    buf.push(domain.len() as u8);
+    buf.extend_from_slice(domain);
+    buf.extend_from_slice(msg);
+    buf
+}
+
+/// Parses an IC root public key, in raw (96 bytes) or DER format.
+pub fn parse_root_key(key: &[u8]) -> Result<Vec<u8>, String> {
+    if key.len() == IC_ROOT_PK_LENGTH {
+        return Ok(key.to_vec());
+    }
+    extract_raw_root_pk_from_der(key)
+}
+
+enum Signer {
+    Canister(ByteBuf),
+    Ed25519(ed25519_dalek::VerifyingKey),
+}
+
+impl Signer {
+    fn ed25519(der: &[u8]) -> Result<Self, String> {
+        let key = der
+            .strip_prefix(&ED25519_DER_PREFIX[..])
+            .ok_or_else(|| "delegation pubkey is not a DER-encoded Ed25519 key".to_string())?;
+        let key: [u8; 32] = key
+            .try_into()
+            .map_err(|_| "invalid Ed25519 public key length".to_string())?;
+        let key = ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(|err| err.to_string())?;
+        Ok(Self::Ed25519(key))
+    }
+
+    fn verify(&self, msg: &[u8], sig: &[u8], root_key: &[u8]) -> Result<(), String> {
+        match self {
+            Self::Canister(pk) => verify_canister_sig(msg, sig, pk, root_key),
+            Self::Ed25519(key) => {
+                let sig =
+                    ed25519_dalek::Signature::from_slice(sig).map_err(|err| err.to_string())?;
+                key.verify_strict(msg, &sig).map_err(|err| err.to_string())
+            }
+        }
+    }
+}
+
+impl IcpToken {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(self, &mut buf).expect("failed to encode in CBOR format");
+        buf
+    }
+
+    /// Decodes and verifies a CBOR-encoded token against the raw IC root public key.
+    /// Returns the claims with the agent set to the canister principal.
+    pub fn verify(data: &[u8], root_key: &[u8]) -> Result<Claims, String> {
+        Self::verify_with_clock(data, root_key, &SystemClock)
+    }
+
+    /// Verifies a token, checking the claims and delegation expirations at the clock's time.
+    /// A delegation restricted to `targets` must include the canister of the token.
+    pub fn verify_with_clock(
+        data: &[u8],
+        root_key: &[u8],
+        clock: &dyn Clock,
+    ) -> Result<Claims, String> {
+        let token: IcpToken = from_reader(data).map_err(|_err| "failed to decode CBOR data")?;
+        let (version, mut claims): (u64, Claims) =
+            from_reader(token.claims.as_slice()).map_err(|_err| "failed to decode CBOR claims")?;
+        if version != TOKEN_V2 {
+            return Err(format!("unsupported claims version {}", version));
+        }
+        let now = clock.now_ms();
+        claims.check_time(now / 1000)?;
+        if token.delegations.len() > MAX_DELEGATIONS {
+            return Err(format!(
+                "too many delegations, at most {} allowed",
+                MAX_DELEGATIONS
+            ));
+        }
+
+        // guard the DER parser against short input
+        if token.public_key.len() < 20 {
+            return Err("invalid canister signature public key".to_string());
+        }
+        let pk = CanisterSigPublicKey::try_from(token.public_key.as_slice())?;
+        let mut signer = Signer::Canister(token.public_key.clone());
+        for (i, sd) in token.delegations.iter().enumerate() {
+            if sd.delegation.expiration < now * 1_000_000 {
+                return Err(format!("delegation {} expired", i));
+            }
+            if let Some(targets) = &sd.delegation.targets {
+                if !targets
+                    .iter()
+                    .any(|t| t.as_slice() == pk.canister_id.as_slice())
+                {
+                    return Err(format!(
+                        "delegation {} does not target canister {}",
+                        i,
+                        pk.canister_id.to_text()
+                    ));
+                }
+            }
+            signer
+                .verify(&sd.delegation.to_message(), &sd.signature, root_key)
+                .map_err(|err| format!("delegation {} verify failed: {}", i, err))?;
+            signer = Signer::ed25519(&sd.delegation.pubkey)?;
+        }
+        signer.verify(&claims_message(&token.claims), &token.signature, root_key)?;
+
+        claims.agent = pk.canister_id.to_text();
+        Ok(claims)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{clock::ManualClock, unix_ms};
+    use candid::Principal;
+    use ed25519_dalek::Signer as _;
+    use ic_certification::{labeled, leaf, Certificate, HashTree};
+    use ic_verify_bls_signature::PrivateKey;
+    use rand_core::OsRng;
+    use sha2::{Digest, Sha256};
+
+    #[derive(Serialize)]
+    struct CanisterSig {
+        certificate: ByteBuf,
+        tree: HashTree,
+    }
+
+    fn sha256(data: &[u8]) -> Vec<u8> {
+        Sha256::digest(data).to_vec()
+    }
+
+    fn self_describing_cbor(value: &impl Serialize) -> Vec<u8> {
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(&ciborium::tag::Required::<_, 55799>(value), &mut buf).unwrap();
+        buf
+    }
+
+    // Builds a canister signature certified by a local BLS root key, see
+    // https://internetcomputer.org/docs/current/references/ic-interface-spec#canister-signatures
+    fn canister_sign(root: &PrivateKey, pk: &CanisterSigPublicKey, msg: &[u8]) -> Vec<u8> {
+        let sig_tree: HashTree = labeled(
+            "sig",
+            labeled(sha256(&pk.seed), labeled(sha256(msg), leaf(b""))),
+        );
+        let cert_tree: HashTree = labeled(
+            "canister",
+            labeled(
+                pk.canister_id.as_slice(),
+                labeled("certified_data", leaf(sig_tree.digest())),
+            ),
+        );
+        let root_msg = signing_message(b"ic-state-root", &cert_tree.digest());
+        let certificate = Certificate {
+            tree: cert_tree,
+            signature: root.sign(&root_msg).serialize().to_vec(),
+            delegation: None,
+        };
+        self_describing_cbor(&CanisterSig {
+            certificate: ByteBuf::from(self_describing_cbor(&certificate)),
+            tree: sig_tree,
+        })
+    }
+
+    fn ed25519_der(key: &ed25519_dalek::SigningKey) -> ByteBuf {
+        ByteBuf::from([&ED25519_DER_PREFIX[..], key.verifying_key().as_bytes()].concat())
+    }
+
+    #[test]
+    fn test_icp_token() {
+        let root = PrivateKey::random(&mut OsRng);
+        let root_key = root.public_key().serialize().to_vec();
+        let canister = Principal::from_text("rwlgt-iiaaa-aaaaa-aaaaa-cai").unwrap();
+        let pk = CanisterSigPublicKey::new(canister, b"proxy".to_vec());
+        let now = unix_ms() / 1000;
+        let claims = Claims {
+            expire_at: now + 3600,
+            issued_at: now,
+            agent: "ignored".to_string(),
+            audience: "proxy1".to_string(),
+            ..Default::default()
+        };
+
+        let raw = ByteBuf::from(claims.to_message());
+
+        // signed by the canister directly
+        let token = IcpToken {
+            claims: raw.clone(),
+            public_key: ByteBuf::from(pk.to_der()),
+            delegations: vec![],
+            signature: ByteBuf::from(canister_sign(&root, &pk, &claims_message(&raw))),
+        };
+        let verified = IcpToken::verify(&token.to_bytes(), &root_key).unwrap();
+        assert_eq!(verified.agent, "rwlgt-iiaaa-aaaaa-aaaaa-cai");
+        assert_eq!(verified.audience, "proxy1");
+
+        let other = PrivateKey::random(&mut OsRng);
+        assert!(IcpToken::verify(&token.to_bytes(), &other.public_key().serialize()).is_err());
+        let mut tampered = token.clone();
+        tampered.claims = ByteBuf::from(
+            Claims {
+                audience: "proxy2".to_string(),
+                ..claims.clone()
+            }
+            .to_message(),
+        );
+        assert!(IcpToken::verify(&tampered.to_bytes(), &root_key).is_err());
+
+        // claims encoded by the issuer in another key order verify over the signed bytes
+        let mut value = ciborium::Value::serialized(&claims).unwrap();
+        if let ciborium::Value::Map(entries) = &mut value {
+            entries.reverse();
+        }
+        let mut reordered = Vec::new();
+        into_writer(&(TOKEN_V2, value), &mut reordered).unwrap();
+        assert_ne!(reordered, raw.to_vec());
+        let token = IcpToken {
+            claims: ByteBuf::from(reordered.clone()),
+            public_key: ByteBuf::from(pk.to_der()),
+            delegations: vec![],
+            signature: ByteBuf::from(canister_sign(&root, &pk, &claims_message(&reordered))),
+        };
+        let verified = IcpToken::verify(&token.to_bytes(), &root_key).unwrap();
+        assert_eq!(verified.audience, "proxy1");
+
+        // signed by a session key through a delegation
+        let session = ed25519_dalek::SigningKey::from_bytes(&[7u8; 32]);
+        let delegation = Delegation {
+            pubkey: ed25519_der(&session),
+            expiration: (now + 600) * 1_000_000_000,
+            targets: None,
+        };
+        let mut token = IcpToken {
+            claims: raw.clone(),
+            public_key: ByteBuf::from(pk.to_der()),
+            delegations: vec![SignedDelegation {
+                signature: ByteBuf::from(canister_sign(&root, &pk, &delegation.to_message())),
+                delegation,
+            }],
+            signature: ByteBuf::from(session.sign(&claims_message(&raw)).to_bytes().to_vec()),
+        };
+        let verified = IcpToken::verify(&token.to_bytes(), &root_key).unwrap();
+        assert_eq!(verified.agent, "rwlgt-iiaaa-aaaaa-aaaaa-cai");
+
+        // the delegation is valid up to its expiration
+        let clock = ManualClock::new((now + 600) * 1000);
+        assert!(IcpToken::verify_with_clock(&token.to_bytes(), &root_key, &clock).is_ok());
+        clock.advance(1);
+        assert_eq!(
+            IcpToken::verify_with_clock(&token.to_bytes(), &root_key, &clock).unwrap_err(),
+            "delegation 0 expired"
+        );
+
+        let attacker = ed25519_dalek::SigningKey::from_bytes(&[8u8; 32]);
+        token.signature = ByteBuf::from(attacker.sign(&claims_message(&raw)).to_bytes().to_vec());
+        assert!(IcpToken::verify(&token.to_bytes(), &root_key).is_err());
+        token.delegations[0].delegation.pubkey = ed25519_der(&attacker);
+        assert!(IcpToken::verify(&token.to_bytes(), &root_key)
+            .unwrap_err()
+            .contains("delegation 0 verify failed"));
+
+        // delegation restricted to targets
+        let other_canister = Principal::from_text("rrkah-fqaaa-aaaaa-aaaaq-cai").unwrap();
+        for (targets, ok) in [
+            (vec![other_canister, canister], true),
+            (vec![other_canister], false),
+            (vec![], false),
+        ] {
+            let delegation = Delegation {
+                pubkey: ed25519_der(&session),
+                expiration: (now + 600) * 1_000_000_000,
+                targets: Some(
+                    targets
+                        .iter()
+                        .map(|t| ByteBuf::from(t.as_slice().to_vec()))
+                        .collect(),
+                ),
+            };
+            token.delegations = vec![SignedDelegation {
+                signature: ByteBuf::from(canister_sign(&root, &pk, &delegation.to_message())),
+                delegation,
+            }];
+            token.signature =
+                ByteBuf::from(session.sign(&claims_message(&raw)).to_bytes().to_vec());
+            let res = IcpToken::verify(&token.to_bytes(), &root_key);
+            if ok {
+                assert_eq!(res.unwrap().agent, "rwlgt-iiaaa-aaaaa-aaaaa-cai");
+            } else {
+                assert_eq!(
+                    res.unwrap_err(),
+                    "delegation 0 does not target canister rwlgt-iiaaa-aaaaa-aaaaa-cai"
+                );
+            }
+        }
+
+        // expired delegation
+        let delegation = Delegation {
+            pubkey: ed25519_der(&session),
+            expiration: (now - 1) * 1_000_000_000,
+            targets: None,
+        };
+        token.delegations = vec![SignedDelegation {
+            signature: ByteBuf::from(canister_sign(&root, &pk, &delegation.to_message())),
+            delegation,
+        }];
+        token.signature = ByteBuf::from(session.sign(&claims_message(&raw)).to_bytes().to_vec());
+        assert_eq!(
+            IcpToken::verify(&token.to_bytes(), &root_key).unwrap_err(),
+            "delegation 0 expired"
+        );
+
+        assert_eq!(
+            parse_root_key(
+                &[
+                    &ic_canister_sig_creation::IC_ROOT_PK_DER_PREFIX[..],
+                    &root_key
+                ]
+                .concat()
+            )
+            .unwrap(),
+            root_key
+        );
+        assert!(parse_root_key(b"invalid").is_err());
+    }
+}
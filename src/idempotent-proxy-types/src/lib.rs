@@ -2,6 +2,10 @@ use http::header::HeaderName;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 pub mod auth;
+pub mod clock;
+#[cfg(feature = "icp")]
+pub mod icp;
+pub mod response;
 
 pub static HEADER_PROXY_AUTHORIZATION: HeaderName = HeaderName::from_static("proxy-authorization");
 pub static HEADER_X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
@@ -9,7 +13,11 @@ pub static HEADER_X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forw
 pub static HEADER_X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
 pub static HEADER_IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
 pub static HEADER_X_JSON_MASK: HeaderName = HeaderName::from_static("x-json-mask");
+pub static HEADER_X_JSON_TRANSFORM: HeaderName = HeaderName::from_static("x-json-transform");
+pub static HEADER_X_JSON_CANONICAL: HeaderName = HeaderName::from_static("x-json-canonical");
 pub static HEADER_RESPONSE_HEADERS: HeaderName = HeaderName::from_static("response-headers");
+pub static HEADER_MAX_RESPONSE_BYTES: HeaderName = HeaderName::from_static("max-response-bytes");
+pub static HEADER_X_PROXY_SIGNATURE: HeaderName = HeaderName::from_static("x-proxy-signature");
 
 pub fn err_string(err: impl std::fmt::Display) -> String {
     err.to_string()
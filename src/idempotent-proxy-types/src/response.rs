@@ -0,0 +1,183 @@
+use base64::{engine::general_purpose, Engine};
+use ciborium::{from_reader, into_writer};
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+use crate::auth::{sha3_256, PublicKey, SigningKey};
+
+const RESPONSE_SIG_DOMAIN: &str = "idempotent-proxy-response";
+
+/// Signature of a proxied response, sent base64url-encoded in the `x-proxy-signature` header.
+/// It covers the status, the signed headers, the SHA3-256 hash of the body,
+/// the idempotency key of the request and the signing time.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResponseSignature {
+    /// Signing time, UNIX timestamp in milliseconds.
+    #[serde(rename = "ts")]
+    pub timestamp: u64,
+    /// Lowercase names of the signed response headers.
+    #[serde(rename = "hdrs")]
+    pub headers: Vec<String>,
+    /// ID of the signing key.
+    #[serde(rename = "kid", default, skip_serializing_if = "Option::is_none")]
+    pub kid: Option<String>,
+    #[serde(rename = "sig")]
+    pub signature: ByteBuf,
+}
+
+impl ResponseSignature {
+    /// Signs a response. All `headers` are signed, their names should be lowercase.
+    pub fn sign(
+        key: &SigningKey,
+        kid: Option<String>,
+        timestamp: u64,
+        idempotency_key: &str,
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> Self {
+        let mut names: Vec<String> = Vec::with_capacity(headers.len());
+        for (k, _) in headers {
+            if !names.contains(k) {
+                names.push(k.clone());
+            }
+        }
+
+        let mut rt = Self {
+            timestamp,
+            headers: names,
+            kid,
+            signature: ByteBuf::new(),
+        };
+        let msg = rt.message(idempotency_key, status, headers, body);
+        rt.signature = ByteBuf::from(key.sign(&msg));
+        rt
+    }
+
+    /// Verifies the signature against a received response.
+    /// `headers` are all the response headers, the signed ones are selected by name.
+    pub fn verify(
+        &self,
+        key: &PublicKey,
+        idempotency_key: &str,
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> Result<(), String> {
+        let msg = self.message(idempotency_key, status, headers, body);
+        key.verify(&msg, &self.signature)
+    }
+
+    /// Returns the signed message: the CBOR-encoded
+    /// [domain, idempotency key, status, [[name, value]], sha3_256(body), timestamp].
+    /// Values of a header are kept in their order, headers in the order of `self.headers`.
+    pub fn message(
+        &self,
+        idempotency_key: &str,
+        status: u16,
+        headers: &[(String, String)],
+        body: &[u8],
+    ) -> Vec<u8> {
+        let mut signed: Vec<(&str, &str)> = Vec::with_capacity(headers.len());
+        for name in &self.headers {
+            for (k, v) in headers {
+                if k.eq_ignore_ascii_case(name) {
+                    signed.push((name, v));
+                }
+            }
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(
+            &(
+                RESPONSE_SIG_DOMAIN,
+                idempotency_key,
+                status,
+                signed,
+                ByteBuf::from(sha3_256(body)),
+                self.timestamp,
+            ),
+            &mut buf,
+        )
+        .expect("failed to encode data in CBOR format");
+        buf
+    }
+
+    pub fn to_header(&self) -> String {
+        let mut buf: Vec<u8> = Vec::new();
+        into_writer(self, &mut buf).expect("failed to encode in CBOR format");
+        general_purpose::URL_SAFE_NO_PAD.encode(buf)
+    }
+
+    pub fn from_header(value: &str) -> Result<Self, String> {
+        let data = general_purpose::URL_SAFE_NO_PAD
+            .decode(value.trim().as_bytes())
+            .map_err(|err| err.to_string())?;
+        from_reader(&data[..]).map_err(|_err| "failed to decode CBOR data".to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use k256::ecdsa;
+    use rand_core::OsRng;
+
+    #[test]
+    fn test_response_signature() {
+        let keys = [
+            SigningKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(&[1u8; 32])),
+            SigningKey::Secp256k1(ecdsa::SigningKey::random(&mut OsRng)),
+        ];
+        let headers = vec![
+            ("content-type".to_string(), "application/json".to_string()),
+            ("x-rate".to_string(), "1".to_string()),
+            ("x-rate".to_string(), "2".to_string()),
+        ];
+        let body = br#"{"result":"0x01"}"#;
+
+        for key in &keys {
+            let sig = ResponseSignature::sign(
+                key,
+                Some("k1".to_string()),
+                1717000000000,
+                "key001",
+                200,
+                &headers,
+                body,
+            );
+            assert_eq!(sig.headers, vec!["content-type", "x-rate"]);
+            let sig = ResponseSignature::from_header(&sig.to_header()).unwrap();
+            assert_eq!(sig.kid.as_deref(), Some("k1"));
+
+            // received headers may be reordered and contain extra headers
+            let received = vec![
+                ("x-rate".to_string(), "1".to_string()),
+                ("Content-Type".to_string(), "application/json".to_string()),
+                (
+                    "date".to_string(),
+                    "Wed, 22 May 2024 12:19:03 GMT".to_string(),
+                ),
+                ("x-rate".to_string(), "2".to_string()),
+            ];
+            let pk = key.public_key();
+            sig.verify(&pk, "key001", 200, &received, body).unwrap();
+
+            assert!(sig.verify(&pk, "key002", 200, &received, body).is_err());
+            assert!(sig.verify(&pk, "key001", 201, &received, body).is_err());
+            assert!(sig
+                .verify(&pk, "key001", 200, &received[..3], body)
+                .is_err());
+            assert!(sig
+                .verify(&pk, "key001", 200, &received, br#"{"result":"0x02"}"#)
+                .is_err());
+            let mut tampered = sig.clone();
+            tampered.timestamp += 1;
+            assert!(tampered
+                .verify(&pk, "key001", 200, &received, body)
+                .is_err());
+        }
+
+        assert!(ResponseSignature::from_header("invalid!").is_err());
+    }
+}